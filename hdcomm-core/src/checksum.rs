@@ -0,0 +1,65 @@
+/// Frame integrity checking.
+///
+/// Every serialized `Message` is followed by a checksum trailer before being
+/// COBS-framed for transmission, so that frames corrupted in transit are
+/// rejected instead of being deserialized into valid-but-wrong messages.
+use crc::{Crc, CRC_16_IBM_SDLC};
+
+pub use crc::Digest;
+
+/// CRC algorithm used for the checksum trailer (CRC-16/X-25).
+pub static CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// Length of the checksum trailer in bytes.
+pub const CHECKSUM_LENGTH: usize = 2;
+
+/// Computes the checksum trailer for a serialized message.
+///
+/// The trailer is transmitted in little-endian byte order.
+pub fn checksum(data: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    CRC.checksum(data).to_le_bytes()
+}
+
+/// Creates a digest for computing the checksum trailer incrementally.
+pub fn digest() -> Digest<'static, u16> {
+    CRC.digest()
+}
+
+/// Verifies the checksum trailer of a decoded frame.
+///
+/// Returns the serialized message without its trailer, or `None` if the
+/// frame is too short to contain a trailer or if the checksum does not match.
+pub fn verify(frame: &[u8]) -> Option<&[u8]> {
+    if frame.len() < CHECKSUM_LENGTH {
+        return None;
+    }
+
+    let (data, trailer) = frame.split_at(frame.len() - CHECKSUM_LENGTH);
+    if checksum(data) == trailer {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_appended_checksum() {
+        let mut frame = [1, 2, 3, 4, 0, 0];
+        let trailer = checksum(&frame[..4]);
+        frame[4..].copy_from_slice(&trailer);
+        assert_eq!(verify(&frame), Some(&frame[..4]));
+
+        frame[1] ^= 0x10;
+        assert_eq!(verify(&frame), None);
+    }
+
+    #[test]
+    fn verify_short_frame() {
+        assert_eq!(verify(&[]), None);
+        assert_eq!(verify(&[0xff]), None);
+    }
+}
@@ -1,9 +1,30 @@
 #![no_std]
 
+pub mod checksum;
 pub mod message;
 pub mod rpc;
 pub mod stream;
 
+/// Version of the protocol defined by this crate.
+///
+/// Bump the minor version whenever messages are appended, and the major
+/// version whenever existing messages change.
+pub const PROTOCOL_VERSION: rpc::Version = rpc::Version { major: 2, minor: 0 };
+
 /// The maximum length of a message in terms of bytes.
 // (FIXME: no elegant way to check yet :()
 pub const MAX_MESSAGE_LENGTH: usize = 256;
+
+/// The maximum length of a frame's contents before COBS encoding, in bytes.
+///
+/// Consists of a serialized message followed by its checksum trailer.
+pub const MAX_FRAME_LENGTH: usize = MAX_MESSAGE_LENGTH + checksum::CHECKSUM_LENGTH;
+
+/// The maximum length of a COBS-encoded frame, including the terminating
+/// zero byte.
+///
+/// Calculated as MAX_FRAME_LENGTH + ceil(MAX_FRAME_LENGTH / 254) + 1.
+pub const MAX_ENCODED_FRAME_LENGTH: usize = MAX_FRAME_LENGTH
+    + (MAX_FRAME_LENGTH / 254)
+    + if (MAX_FRAME_LENGTH % 254) > 0 { 1 } else { 0 }
+    + 1;
@@ -47,6 +47,129 @@ pub enum Payload {
     /// VIN voltage reading request.
     VinReadingReq(VinReadingReqBody),
     VinReadingRep(VinReadingRepBody),
+
+    /// Handshake request.
+    ///
+    /// Exchanged on connection to check that the host and device speak
+    /// compatible versions of the protocol.
+    HandshakeReq(HandshakeReqBody),
+    HandshakeRep(HandshakeRepBody),
+
+    /// Actuator lease request.
+    ///
+    /// Has the device stop its actuators unless the host keeps commanding
+    /// them.
+    LeaseReq(LeaseReqBody),
+    LeaseRep(LeaseRepBody),
+
+    /// Front distance stream configuration request.
+    FrontDistanceStreamReq(FrontDistanceStreamReqBody),
+    FrontDistanceStreamRep(FrontDistanceStreamRepBody),
+}
+
+impl Payload {
+    /// Remote procedure this request or reply belongs to.
+    pub fn procedure(&self) -> Procedure {
+        match self {
+            Self::PingReq(_) | Self::PingRep(_) => Procedure::Ping,
+            Self::MoveReq(_) | Self::MoveRep(_) => Procedure::Move,
+            Self::MoveStatusReq(_) | Self::MoveStatusRep(_) => Procedure::MoveStatus,
+            Self::MoveCancelReq(_) | Self::MoveCancelRep(_) => Procedure::MoveCancel,
+            Self::PidParamUpdateReq(_) | Self::PidParamUpdateRep(_) => Procedure::PidParamUpdate,
+            Self::RawTeleOpReq(_) | Self::RawTeleOpRep(_) => Procedure::RawTeleOp,
+            Self::FrontDistanceReq(_) | Self::FrontDistanceRep(_) => Procedure::FrontDistance,
+            Self::VinReadingReq(_) | Self::VinReadingRep(_) => Procedure::VinReading,
+            Self::HandshakeReq(_) | Self::HandshakeRep(_) => Procedure::Handshake,
+            Self::LeaseReq(_) | Self::LeaseRep(_) => Procedure::Lease,
+            Self::FrontDistanceStreamReq(_) | Self::FrontDistanceStreamRep(_) => {
+                Procedure::FrontDistanceStream
+            }
+        }
+    }
+}
+
+/// Remote procedures.
+///
+/// Discriminants are the indices of procedures in a `Procedures` set, and
+/// must never be changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Procedure {
+    Ping = 0,
+    Move = 1,
+    MoveStatus = 2,
+    MoveCancel = 3,
+    PidParamUpdate = 4,
+    RawTeleOp = 5,
+    FrontDistance = 6,
+    VinReading = 7,
+    Handshake = 8,
+    Lease = 9,
+    FrontDistanceStream = 10,
+}
+
+/// Set of remote procedures.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Procedures(pub u64);
+
+impl Procedures {
+    /// Procedures supported by devices that predate the handshake, which
+    /// speak version 1.0 of the protocol.
+    pub const LEGACY: Self = Self::empty()
+        .with(Procedure::Ping)
+        .with(Procedure::Move)
+        .with(Procedure::MoveStatus)
+        .with(Procedure::MoveCancel)
+        .with(Procedure::PidParamUpdate)
+        .with(Procedure::RawTeleOp)
+        .with(Procedure::FrontDistance)
+        .with(Procedure::VinReading);
+
+    /// All procedures in this version of the protocol.
+    pub const ALL: Self = Self::LEGACY
+        .with(Procedure::Handshake)
+        .with(Procedure::Lease)
+        .with(Procedure::FrontDistanceStream);
+
+    /// Create an empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the set with a procedure added.
+    pub const fn with(self, procedure: Procedure) -> Self {
+        Self(self.0 | (1 << procedure as u64))
+    }
+
+    /// Whether the set contains a procedure.
+    pub const fn contains(self, procedure: Procedure) -> bool {
+        self.0 & (1 << procedure as u64) != 0
+    }
+}
+
+/// Protocol version.
+///
+/// The major version is incremented on changes that break existing
+/// messages. The minor version is incremented when messages are appended,
+/// which older peers simply fail to decode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    /// Whether a peer speaking this version can communicate with a peer
+    /// speaking `other`.
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        self.major == other.major
+    }
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
 }
 
 pub type PingReqBody = ();
@@ -62,6 +185,11 @@ pub struct PingRepBody {
 /// Body of a move request.
 ///
 /// All units are in encoder counts.
+///
+/// Moves starting at a non-zero velocity (`params.conditions.v0`) continue
+/// the move being executed, and are queued to start as soon as it
+/// completes. They are only accepted while the device executes a move ending
+/// at the same velocity, with no other move queued.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct MoveReqBody {
     /// Calculated S-Curve parameters.
@@ -80,6 +208,10 @@ pub struct MoveReqBody {
     pub steering_setup_ms: u16,
     /// Whether this move should be done in reverse.
     pub reverse: bool,
+    /// Sequence number of the move, chosen by the host.
+    ///
+    /// Echoed in the lifecycle events of the move.
+    pub seq: u16,
 }
 
 impl MoveReqBody {
@@ -95,6 +227,12 @@ pub enum MoveRepBody {
     Busy,
     /// Move command accepted.
     Accepted,
+    /// Move command queued, to start once the move being executed
+    /// completes.
+    Queued,
+    /// Move command rejected, as it starts at a non-zero velocity that does
+    /// not continue the move being executed.
+    Rejected,
 }
 
 pub type MoveStatusReqBody = ();
@@ -195,6 +333,22 @@ pub struct FrontDistanceRepBody {
     pub distance: Option<f32>,
 }
 
+/// Body of a front distance stream configuration request.
+///
+/// Has the device stream front distance sensor readings, in addition to
+/// answering front distance reading requests.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FrontDistanceStreamReqBody {
+    /// Interval between streamed readings.
+    ///
+    /// `0` stops the stream.
+    ///
+    /// In units of milliseconds.
+    pub interval_ms: u16,
+}
+
+pub type FrontDistanceStreamRepBody = ();
+
 pub type VinReadingReqBody = ();
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -205,3 +359,39 @@ pub struct VinReadingRepBody {
     /// Measured voltage in volts.
     pub vin: f32,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HandshakeReqBody {
+    /// Protocol version spoken by the host.
+    pub version: Version,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HandshakeRepBody {
+    /// Protocol version spoken by the device.
+    pub version: Version,
+    /// Firmware build identifier.
+    pub build_id: u32,
+    /// Remote procedures supported by the device.
+    pub procedures: Procedures,
+}
+
+/// Body of an actuator lease request.
+///
+/// While the lease is held, the device stops its actuators, aborting any
+/// move being executed, once no lease request, move command or raw teleop
+/// command was received for the lease duration. This prevents the robot
+/// from driving on if the host crashes. Expiry is reported on the stream
+/// channel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LeaseReqBody {
+    /// Lease duration.
+    ///
+    /// `0` releases the lease, leaving the actuators running without
+    /// supervision.
+    ///
+    /// In units of milliseconds.
+    pub duration_ms: u32,
+}
+
+pub type LeaseRepBody = ();
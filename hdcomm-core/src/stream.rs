@@ -12,6 +12,14 @@ pub struct Message {
 pub enum Payload {
     /// Payload contains an AHRS sample.
     Ahrs(AhrsBody),
+    /// Payload contains a move lifecycle event.
+    Move(MoveEvent),
+    /// Payload contains wheel odometry.
+    Odometry(OdometryBody),
+    /// Payload contains a failsafe event.
+    Failsafe(FailsafeEvent),
+    /// Payload contains a front distance sensor reading.
+    FrontDistance(FrontDistanceBody),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -30,3 +38,80 @@ pub struct AhrsBody {
     /// Device timestamp.
     pub time_ms: u32,
 }
+
+/// Move lifecycle event.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoveEvent {
+    /// Device timestamp.
+    pub time_ms: u32,
+    /// Sequence number of the move the event relates to, as sent in its
+    /// move request.
+    pub seq: u16,
+    /// Event kind.
+    pub kind: MoveEventKind,
+}
+
+/// Kind of move lifecycle event.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MoveEventKind {
+    /// A move was accepted, and steering is being set up.
+    Started,
+    /// Steering has settled, and the drive wheels started moving.
+    SteeringSettled,
+    /// The move ran to completion.
+    Completed(MoveSummary),
+    /// The move was stopped before completion, e.g. by a cancel request.
+    Aborted(MoveSummary),
+}
+
+/// State of the drive wheels at the end of a move.
+///
+/// All arrays are `[left, right]`. All units are in encoder counts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoveSummary {
+    /// Encoder counts travelled since the start of the move.
+    pub encoder_counts: [i32; 2],
+    /// Position tracking error of each wheel's control loop.
+    pub tracking_error: [f32; 2],
+}
+
+/// Wheel odometry sample.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OdometryBody {
+    /// Encoder counts of the `[left, right]` wheels since the start of the
+    /// device.
+    ///
+    /// Counts increase when the wheels drive forwards, and wrap around on
+    /// overflow.
+    pub encoder_counts: [i32; 2],
+    /// Device timestamp.
+    pub time_ms: u32,
+}
+
+/// Failsafe event, sent when the device stops its actuators as the actuator
+/// lease held by the host expired.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FailsafeEvent {
+    /// Device timestamp.
+    pub time_ms: u32,
+    /// Duration of the expired lease.
+    ///
+    /// In units of milliseconds.
+    pub lease_ms: u32,
+}
+
+/// Front distance sensor reading.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FrontDistanceBody {
+    /// Measurement start time, as measured by the device.
+    pub start_time_ms: u32,
+    /// Measurement end time, as measured by the device.
+    pub end_time_ms: u32,
+    /// Distance measurement. `None` if an error occurred during measurement.
+    ///
+    /// Distances beyond the maximum detection range are clamped to the
+    /// maximum detection range.
+    ///
+    /// In units of metres.
+    pub distance: Option<f32>,
+}
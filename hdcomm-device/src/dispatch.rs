@@ -0,0 +1,270 @@
+/// Device-side RPC dispatching.
+///
+/// Routes requests received from the host to the firmware's `Handler`, and
+/// produces correctly-typed replies.
+use crate::{Accumulator, FeedResult, FrameStats};
+use hdcomm_core::message::{self, Message};
+use hdcomm_core::rpc::{self, *};
+
+/// Macro declaring the handler for remote procedures, along with the
+/// function dispatching requests to it.
+///
+/// - `name`: name of handler method
+/// - `request`: enum variant of RPC request
+/// - `request_body`: type of request body
+/// - `response`: enum variant of RPC response
+/// - `response_body`: type of response body.
+macro_rules! remote_procedure_handlers {
+    (
+        $(
+            $name:ident,
+            $request:path, $request_body:path,
+            $response:path, $response_body:path
+        );+
+    ) => {
+        /// Handler for remote procedures called by the host.
+        ///
+        /// Implemented by the firmware, with one method per request.
+        ///
+        /// Handshakes are answered by `dispatch` itself.
+        pub trait Handler {
+            $(fn $name(&mut self, body: $request_body) -> $response_body;)+
+
+            /// Firmware build identifier reported to the host on handshake.
+            fn build_id(&self) -> u32 {
+                0
+            }
+        }
+
+        /// Calls the handler method corresponding to a request, and produces
+        /// the reply to be sent back to the host.
+        ///
+        /// Returns `None` if the message is not a request.
+        pub fn dispatch<H: Handler + ?Sized>(
+            handler: &mut H,
+            request: rpc::Message,
+        ) -> Option<rpc::Message> {
+            let payload = match request.payload {
+                $($request(body) => $response(handler.$name(body)),)+
+                Payload::HandshakeReq(_) => Payload::HandshakeRep(HandshakeRepBody {
+                    version: hdcomm_core::PROTOCOL_VERSION,
+                    build_id: handler.build_id(),
+                    procedures: Procedures::ALL,
+                }),
+                _ => return None,
+            };
+
+            Some(rpc::Message {
+                id: request.id,
+                payload,
+            })
+        }
+    };
+}
+
+remote_procedure_handlers!(
+    ping,
+        Payload::PingReq, PingReqBody,
+        Payload::PingRep, PingRepBody;
+    move_cmd,
+        Payload::MoveReq, MoveReqBody,
+        Payload::MoveRep, MoveRepBody;
+    move_status,
+        Payload::MoveStatusReq, MoveStatusReqBody,
+        Payload::MoveStatusRep, MoveStatusRepBody;
+    move_cancel,
+        Payload::MoveCancelReq, MoveCancelReqBody,
+        Payload::MoveCancelRep, MoveCancelRepBody;
+    pid_param_update,
+        Payload::PidParamUpdateReq, PidParamUpdateReqBody,
+        Payload::PidParamUpdateRep, PidParamUpdateRepBody;
+    raw_teleop,
+        Payload::RawTeleOpReq, RawTeleOpReqBody,
+        Payload::RawTeleOpRep, RawTeleOpRepBody;
+    get_front_distance,
+        Payload::FrontDistanceReq, FrontDistanceReqBody,
+        Payload::FrontDistanceRep, FrontDistanceRepBody;
+    get_vin_reading,
+        Payload::VinReadingReq, VinReadingReqBody,
+        Payload::VinReadingRep, VinReadingRepBody;
+    lease,
+        Payload::LeaseReq, LeaseReqBody,
+        Payload::LeaseRep, LeaseRepBody;
+    front_distance_stream,
+        Payload::FrontDistanceStreamReq, FrontDistanceStreamReqBody,
+        Payload::FrontDistanceStreamRep, FrontDistanceStreamRepBody
+);
+
+/// Dispatches requests received from the host to a `Handler`.
+pub struct Dispatcher {
+    accumulator: Accumulator,
+}
+
+impl Dispatcher {
+    /// Create a new dispatcher.
+    pub const fn new() -> Self {
+        Self {
+            accumulator: Accumulator::new(),
+        }
+    }
+
+    /// Reset the dispatcher, dropping any partially received frame.
+    pub fn reset(&mut self) {
+        self.accumulator.reset()
+    }
+
+    /// Counters of frames received from the host.
+    pub fn stats(&self) -> FrameStats {
+        self.accumulator.stats()
+    }
+
+    /// Feed the dispatcher with data received from the host.
+    ///
+    /// Each request completed by the data is dispatched to `handler`, and
+    /// `reply` is called with the resulting reply, which should be framed
+    /// and sent to the host.
+    ///
+    /// Frames that are corrupted, and messages that are not requests, are
+    /// dropped.
+    pub fn feed<H, F>(&mut self, handler: &mut H, mut input: &[u8], mut reply: F)
+    where
+        H: Handler + ?Sized,
+        F: FnMut(Message),
+    {
+        while !input.is_empty() {
+            input = match self.accumulator.feed(input) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(remaining)
+                | FeedResult::ChecksumError(remaining)
+                | FeedResult::DeserError(remaining) => remaining,
+                FeedResult::Success { data, remaining } => {
+                    if let message::Payload::RPC(request) = data.payload {
+                        if let Some(response) = dispatch(handler, request) {
+                            reply(Message {
+                                payload: message::Payload::RPC(response),
+                            });
+                        }
+                    }
+                    remaining
+                }
+            };
+        }
+    }
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Handler recording the last procedure called.
+    #[derive(Default)]
+    struct Device {
+        called: Option<&'static str>,
+    }
+
+    impl Handler for Device {
+        fn ping(&mut self, _: PingReqBody) -> PingRepBody {
+            self.called = Some("ping");
+            PingRepBody { time_ms: 1000 }
+        }
+
+        fn move_cmd(&mut self, _: MoveReqBody) -> MoveRepBody {
+            self.called = Some("move_cmd");
+            MoveRepBody::Busy
+        }
+
+        fn move_status(&mut self, _: MoveStatusReqBody) -> MoveStatusRepBody {
+            self.called = Some("move_status");
+            MoveStatusRepBody::NoCommand
+        }
+
+        fn move_cancel(&mut self, _: MoveCancelReqBody) -> MoveCancelRepBody {
+            self.called = Some("move_cancel");
+        }
+
+        fn pid_param_update(&mut self, _: PidParamUpdateReqBody) -> PidParamUpdateRepBody {
+            self.called = Some("pid_param_update");
+            PidParamUpdateRepBody::Busy
+        }
+
+        fn raw_teleop(&mut self, _: RawTeleOpReqBody) -> RawTeleOpRepBody {
+            self.called = Some("raw_teleop");
+            RawTeleOpRepBody::Busy
+        }
+
+        fn get_front_distance(&mut self, _: FrontDistanceReqBody) -> FrontDistanceRepBody {
+            self.called = Some("get_front_distance");
+            FrontDistanceRepBody {
+                start_time_ms: 1000,
+                end_time_ms: 1000,
+                distance: None,
+            }
+        }
+
+        fn get_vin_reading(&mut self, _: VinReadingReqBody) -> VinReadingRepBody {
+            self.called = Some("get_vin_reading");
+            VinReadingRepBody {
+                time_ms: 1000,
+                vin: 12.,
+            }
+        }
+
+        fn lease(&mut self, _: LeaseReqBody) -> LeaseRepBody {
+            self.called = Some("lease");
+        }
+
+        fn front_distance_stream(
+            &mut self,
+            _: FrontDistanceStreamReqBody,
+        ) -> FrontDistanceStreamRepBody {
+            self.called = Some("front_distance_stream");
+        }
+    }
+
+    #[test]
+    fn dispatch_request_frame() {
+        let request = Message {
+            payload: message::Payload::RPC(rpc::Message {
+                id: 7,
+                payload: Payload::VinReadingReq(()),
+            }),
+        };
+        let mut buf = [0; crate::ENCODED_BUFFER_SIZE];
+        let frame = crate::frame(&request, &mut buf).unwrap();
+
+        let mut device = Device::default();
+        let mut encoded = [0; crate::ENCODED_BUFFER_SIZE];
+        let mut len = 0;
+        Dispatcher::new().feed(&mut device, frame, |reply| {
+            assert_eq!(len, 0, "more than one reply");
+            len = crate::frame(&reply, &mut encoded).unwrap().len();
+        });
+        assert_eq!(device.called, Some("get_vin_reading"));
+
+        // The encoded reply answers the request.
+        match Accumulator::new().feed(&encoded[..len]) {
+            FeedResult::Success { data, remaining } => {
+                assert!(remaining.is_empty());
+                assert_eq!(
+                    data,
+                    Message {
+                        payload: message::Payload::RPC(rpc::Message {
+                            id: 7,
+                            payload: Payload::VinReadingRep(VinReadingRepBody {
+                                time_ms: 1000,
+                                vin: 12.,
+                            }),
+                        }),
+                    }
+                );
+            }
+            _ => panic!("reply not decoded"),
+        }
+    }
+}
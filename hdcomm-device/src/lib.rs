@@ -1,54 +1,251 @@
 #![no_std]
 use core::ops::{Index, IndexMut};
 
-use hdcomm_core::message::Message;
-use postcard::{
-    flavors::{Cobs, SerFlavor},
-    CobsAccumulator, FeedResult,
+pub mod dispatch;
+pub mod watchdog;
+
+use hdcomm_core::{
+    checksum::{self, Digest},
+    message::{self, Message},
+    stream,
 };
+use postcard::flavors::{Cobs, SerFlavor, Slice};
 use serde::Serialize;
 
+/// Maximum number of bytes required for a serialized `Message` framed using
+/// COBS, including its checksum trailer and the terminating zero byte.
+pub const ENCODED_BUFFER_SIZE: usize = hdcomm_core::MAX_ENCODED_FRAME_LENGTH;
+
+/// The result of feeding the accumulator.
+pub enum FeedResult<'a> {
+    /// Consumed all data, still pending.
+    Consumed,
+    /// Buffer was filled. Contains remaining section of input, if any.
+    OverFull(&'a [u8]),
+    /// Reached end of frame, but the frame was corrupted. Contains remaining
+    /// section of input, if any.
+    ChecksumError(&'a [u8]),
+    /// Reached end of frame, but deserialization failed. Contains remaining
+    /// section of input, if any.
+    DeserError(&'a [u8]),
+    /// Deserialization complete. Contains deserialized data and remaining
+    /// section of input, if any.
+    Success {
+        /// Deserialized message.
+        data: Message,
+        /// Remaining data left in the buffer after deserializing.
+        remaining: &'a [u8],
+    },
+}
+
+/// Counters of frames processed by an `Accumulator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Number of messages successfully received.
+    pub received: u32,
+    /// Number of frames dropped because they were corrupted, as their COBS
+    /// encoding was invalid or their checksum did not match.
+    pub checksum_errors: u32,
+    /// Number of frames dropped because they could not be deserialized.
+    pub deserialization_errors: u32,
+    /// Number of frames dropped because they overflowed the buffer.
+    pub overflows: u32,
+}
+
+impl FrameStats {
+    /// Total number of frames dropped.
+    pub fn dropped(&self) -> u32 {
+        self.checksum_errors
+            .wrapping_add(self.deserialization_errors)
+            .wrapping_add(self.overflows)
+    }
+}
+
 /// An accumulator that consumes message data and returns deserialized
 /// `Message`s.
-pub struct Accumulator(CobsAccumulator<{ hdcomm_core::MAX_MESSAGE_LENGTH }>);
+///
+/// Frames that fail integrity checking are dropped.
+pub struct Accumulator {
+    buf: [u8; ENCODED_BUFFER_SIZE],
+    idx: usize,
+    stats: FrameStats,
+}
 
 impl Accumulator {
     /// Create a new accumulator.
     pub const fn new() -> Self {
-        Self(CobsAccumulator::new())
+        Self {
+            buf: [0; ENCODED_BUFFER_SIZE],
+            idx: 0,
+            stats: FrameStats {
+                received: 0,
+                checksum_errors: 0,
+                deserialization_errors: 0,
+                overflows: 0,
+            },
+        }
     }
 
     /// Reset the accumulator.
+    ///
+    /// Frame counters are preserved.
     pub fn reset(&mut self) {
-        self.0 = CobsAccumulator::new()
+        self.idx = 0;
+    }
+
+    /// Counters of frames processed by this accumulator.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
     }
 
     /// Feed the accumulator.
-    /// See the documentation for `postcard::CobsAccumulator` for more
-    /// information.
-    pub fn feed<'a>(&mut self, buf: &'a [u8]) -> FeedResult<'a, Message> {
-        self.0.feed(buf)
+    ///
+    /// Behaves like `postcard::CobsAccumulator`, with the addition of
+    /// verifying the checksum trailer of each frame before deserializing it.
+    pub fn feed<'a>(&mut self, mut input: &'a [u8]) -> FeedResult<'a> {
+        loop {
+            if input.is_empty() {
+                return FeedResult::Consumed;
+            }
+
+            let (take, release, end_of_frame) = match input.iter().position(|&i| i == 0) {
+                // Include the zero in the "take" portion of the input.
+                Some(n) => {
+                    let (take, release) = input.split_at(n + 1);
+                    (take, release, true)
+                }
+                None => (input, &input[input.len()..], false),
+            };
+
+            if (self.idx + take.len()) > self.buf.len() {
+                self.idx = 0;
+                self.stats.overflows = self.stats.overflows.wrapping_add(1);
+                return FeedResult::OverFull(release);
+            }
+
+            self.buf[self.idx..self.idx + take.len()].copy_from_slice(take);
+            self.idx += take.len();
+
+            if !end_of_frame {
+                return FeedResult::Consumed;
+            }
+
+            // Exclude the terminating zero from the COBS-encoded frame.
+            let len = self.idx - 1;
+            self.idx = 0;
+
+            // Skip empty frames caused by consecutive delimiters.
+            if len == 0 {
+                input = release;
+                continue;
+            }
+
+            return self.decode(len, release);
+        }
+    }
+
+    /// Decode the COBS-encoded frame of length `len` held in the buffer.
+    fn decode<'a>(&mut self, len: usize, remaining: &'a [u8]) -> FeedResult<'a> {
+        let frame = &mut self.buf[..len];
+
+        // Invalid COBS encoding is a sign of corruption in transit.
+        let len = match postcard_cobs::decode_in_place(frame) {
+            Ok(len) => len,
+            Err(_) => {
+                self.stats.checksum_errors = self.stats.checksum_errors.wrapping_add(1);
+                return FeedResult::ChecksumError(remaining);
+            }
+        };
+
+        let data = match checksum::verify(&frame[..len]) {
+            Some(data) => data,
+            None => {
+                self.stats.checksum_errors = self.stats.checksum_errors.wrapping_add(1);
+                return FeedResult::ChecksumError(remaining);
+            }
+        };
+
+        match postcard::from_bytes(data) {
+            Ok(data) => {
+                self.stats.received = self.stats.received.wrapping_add(1);
+                FeedResult::Success { data, remaining }
+            }
+            Err(_) => {
+                self.stats.deserialization_errors =
+                    self.stats.deserialization_errors.wrapping_add(1);
+                FeedResult::DeserError(remaining)
+            }
+        }
     }
 }
 
-/// Maximum number of bytes required for a serialized `Message` framed using
-/// COBS.
-///
-/// Calculated as ceil(MAX_MESSAGE_LENGTH / 254).
-pub const ENCODED_BUFFER_SIZE: usize = hdcomm_core::MAX_MESSAGE_LENGTH
-    + (hdcomm_core::MAX_MESSAGE_LENGTH / 254)
-    + if (hdcomm_core::MAX_MESSAGE_LENGTH % 254) > 0 {
-        1
-    } else {
-        0
-    };
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Serializes a `Message` and writes its COBS-framed version to a buffer.
 ///
 /// This buffer should be at least `ENCODED_BUFFER_SIZE` in length to avoid
 /// errors due to insufficient outptu buffer space.
 pub fn frame<'a>(message: &Message, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
-    postcard::to_slice_cobs(message, buf)
+    postcard::serialize_with_flavor(message, Checksum::new(Cobs::try_new(Slice::new(buf))?))
+}
+
+/// Wraps a stream payload in a `Message`, and writes its COBS-framed version
+/// to a buffer.
+///
+/// See `frame` for the requirements on the buffer.
+pub fn frame_stream(
+    payload: stream::Payload,
+    buf: &mut [u8],
+) -> Result<&mut [u8], postcard::Error> {
+    frame(
+        &Message {
+            payload: message::Payload::Stream(stream::Message { payload }),
+        },
+        buf,
+    )
+}
+
+/// The `Checksum` flavor appends the checksum trailer to the serialized data
+/// before passing it on to the wrapped flavor.
+pub struct Checksum<B: SerFlavor> {
+    flav: B,
+    digest: Digest<'static, u16>,
+}
+
+impl<B: SerFlavor> Checksum<B> {
+    /// Create a new `Checksum` modifier flavor.
+    pub fn new(flav: B) -> Self {
+        Self {
+            flav,
+            digest: checksum::digest(),
+        }
+    }
+}
+
+impl<B: SerFlavor> SerFlavor for Checksum<B> {
+    type Output = <B as SerFlavor>::Output;
+
+    #[inline(always)]
+    fn try_extend(&mut self, data: &[u8]) -> core::result::Result<(), ()> {
+        self.digest.update(data);
+        self.flav.try_extend(data)
+    }
+
+    #[inline(always)]
+    fn try_push(&mut self, data: u8) -> core::result::Result<(), ()> {
+        self.digest.update(&[data]);
+        self.flav.try_push(data)
+    }
+
+    fn release(mut self) -> core::result::Result<Self::Output, ()> {
+        self.flav
+            .try_extend(&self.digest.finalize().to_le_bytes())?;
+        self.flav.release()
+    }
 }
 
 /// The `HVecRef` flavor is a wrapper type around a reference to a `heapless::Vec`.
@@ -90,6 +287,8 @@ impl<const B: usize> SerFlavor for HVecRef<'_, B> {
 }
 
 /// Serializes a `Message` into an existing heapless Vec.
+///
+/// The serialized data is followed by its checksum trailer and COBS-framed.
 pub fn into_vec<T, const N: usize>(
     value: &T,
     result: &mut heapless::Vec<u8, N>,
@@ -97,5 +296,71 @@ pub fn into_vec<T, const N: usize>(
 where
     T: Serialize + ?Sized,
 {
-    postcard::serialize_with_flavor::<T, _, _>(value, Cobs::try_new(HVecRef(result))?)
+    postcard::serialize_with_flavor::<T, _, _>(
+        value,
+        Checksum::new(Cobs::try_new(HVecRef(result))?),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hdcomm_core::rpc;
+
+    fn ping() -> Message {
+        Message {
+            payload: message::Payload::RPC(rpc::Message {
+                id: 1,
+                payload: rpc::Payload::PingReq(()),
+            }),
+        }
+    }
+
+    #[test]
+    fn feed_framed_message() {
+        let mut buf = [0; ENCODED_BUFFER_SIZE];
+        let frame = frame(&ping(), &mut buf).unwrap();
+
+        let mut accumulator = Accumulator::new();
+        match accumulator.feed(frame) {
+            FeedResult::Success { data, remaining } => {
+                assert_eq!(data, ping());
+                assert!(remaining.is_empty());
+            }
+            _ => panic!("frame not decoded"),
+        }
+        assert_eq!(accumulator.stats().received, 1);
+    }
+
+    #[test]
+    fn corrupted_frame_rejected() {
+        let mut data = [0; ENCODED_BUFFER_SIZE];
+        let len = postcard::to_slice(&ping(), &mut data).unwrap().len();
+        let trailer = checksum::checksum(&data[..len]);
+        data[len..len + checksum::CHECKSUM_LENGTH].copy_from_slice(&trailer);
+        data[0] ^= 0x01;
+
+        let mut buf = [0; ENCODED_BUFFER_SIZE];
+        let len = postcard_cobs::encode(&data[..len + checksum::CHECKSUM_LENGTH], &mut buf);
+
+        let mut accumulator = Accumulator::new();
+        assert!(matches!(
+            accumulator.feed(&buf[..len + 1]),
+            FeedResult::ChecksumError(_)
+        ));
+        assert_eq!(accumulator.stats().checksum_errors, 1);
+        assert_eq!(accumulator.stats().received, 0);
+    }
+
+    #[test]
+    fn invalid_cobs_frame_rejected() {
+        let mut accumulator = Accumulator::new();
+        // The code byte points past the end of the frame.
+        assert!(matches!(
+            accumulator.feed(&[5, 1, 0]),
+            FeedResult::ChecksumError(_)
+        ));
+        assert_eq!(accumulator.stats().checksum_errors, 1);
+        assert_eq!(accumulator.stats().deserialization_errors, 0);
+    }
 }
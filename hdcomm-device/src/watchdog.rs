@@ -0,0 +1,132 @@
+/// Actuator lease tracking.
+///
+/// Tracks the actuator lease held by the host, so that the firmware can stop
+/// the actuators once the host stops commanding them, e.g. because it
+/// crashed in the middle of teleoperation.
+use hdcomm_core::rpc::LeaseReqBody;
+use hdcomm_core::stream::{FailsafeEvent, Payload};
+
+/// Tracks the actuator lease held by the host.
+///
+/// The firmware should:
+/// - call `lease` on receiving a lease request,
+/// - call `renew` on receiving a move or raw teleop command,
+/// - call `poll` periodically, stopping its actuators and sending the
+///   returned payload on the stream channel once the lease expires.
+///
+/// All times are device timestamps in milliseconds, which may wrap around.
+pub struct Watchdog {
+    /// Duration of the lease held, if any.
+    duration_ms: Option<u32>,
+    /// Time the lease was last renewed at.
+    renewed_ms: u32,
+}
+
+impl Watchdog {
+    /// Create a new watchdog, with no lease held.
+    pub const fn new() -> Self {
+        Self {
+            duration_ms: None,
+            renewed_ms: 0,
+        }
+    }
+
+    /// Handle a lease request received at `now_ms`.
+    pub fn lease(&mut self, body: &LeaseReqBody, now_ms: u32) {
+        self.duration_ms = match body.duration_ms {
+            0 => None,
+            duration_ms => Some(duration_ms),
+        };
+        self.renewed_ms = now_ms;
+    }
+
+    /// Renew the lease, if held, on receiving a command at `now_ms`.
+    pub fn renew(&mut self, now_ms: u32) {
+        self.renewed_ms = now_ms;
+    }
+
+    /// Whether a lease is held.
+    pub fn is_held(&self) -> bool {
+        self.duration_ms.is_some()
+    }
+
+    /// Check whether the lease expired at `now_ms`.
+    ///
+    /// Returns the failsafe event to be sent to the host if the lease
+    /// expired, in which case the actuators must be stopped. The lease is
+    /// released on expiry.
+    pub fn poll(&mut self, now_ms: u32) -> Option<Payload> {
+        let duration_ms = self.duration_ms?;
+        if now_ms.wrapping_sub(self.renewed_ms) < duration_ms {
+            return None;
+        }
+
+        self.duration_ms = None;
+
+        Some(Payload::Failsafe(FailsafeEvent {
+            time_ms: now_ms,
+            lease_ms: duration_ms,
+        }))
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_expiry_without_lease() {
+        let mut watchdog = Watchdog::new();
+        assert!(!watchdog.is_held());
+        assert_eq!(watchdog.poll(u32::MAX), None);
+    }
+
+    #[test]
+    fn lease_expires_across_clock_wraparound() {
+        let mut watchdog = Watchdog::new();
+        watchdog.lease(&LeaseReqBody { duration_ms: 100 }, u32::MAX - 49);
+        assert_eq!(watchdog.poll(u32::MAX), None);
+        assert_eq!(watchdog.poll(49), None);
+        assert_eq!(
+            watchdog.poll(50),
+            Some(Payload::Failsafe(FailsafeEvent {
+                time_ms: 50,
+                lease_ms: 100,
+            }))
+        );
+
+        // The lease is released on expiry.
+        assert!(!watchdog.is_held());
+        assert_eq!(watchdog.poll(1000), None);
+    }
+
+    #[test]
+    fn renewal_across_clock_wraparound() {
+        let mut watchdog = Watchdog::new();
+        watchdog.lease(&LeaseReqBody { duration_ms: 100 }, u32::MAX - 9);
+        watchdog.renew(20);
+        assert_eq!(watchdog.poll(119), None);
+        assert_eq!(
+            watchdog.poll(120),
+            Some(Payload::Failsafe(FailsafeEvent {
+                time_ms: 120,
+                lease_ms: 100,
+            }))
+        );
+    }
+
+    #[test]
+    fn zero_duration_releases_lease() {
+        let mut watchdog = Watchdog::new();
+        watchdog.lease(&LeaseReqBody { duration_ms: 100 }, 0);
+        watchdog.lease(&LeaseReqBody { duration_ms: 0 }, 10);
+        assert!(!watchdog.is_held());
+        assert_eq!(watchdog.poll(1000), None);
+    }
+}
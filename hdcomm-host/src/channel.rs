@@ -1,20 +1,34 @@
 /// Underlying transport channel for RPCs.
-use crate::codec::Codec;
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use crate::codec::{Codec, Counters};
+use crate::transport::{Endpoint, Transport};
+use futures::stream::SplitSink;
+use hdcomm_core::message::Message;
+use std::sync::Arc;
 use tokio_util::codec::Framed;
 
 /// Type of the framed transport channel.
-pub(crate) type FramedChannel = Framed<SerialStream, Codec<{ hdcomm_core::MAX_MESSAGE_LENGTH }>>;
+pub(crate) type FramedChannel =
+    Framed<Box<dyn Transport>, Codec<{ hdcomm_core::MAX_ENCODED_FRAME_LENGTH }>>;
 
-/// Creates a new framed transport channel using a given serial port and the
-/// provided baud rate.
-pub(crate) async fn new(path: &str, baud_rate: u32) -> Result<FramedChannel, tokio_serial::Error> {
-    let stream = tokio_serial::new(path, baud_rate)
-        .parity(tokio_serial::Parity::None)
-        .data_bits(tokio_serial::DataBits::Eight)
-        .stop_bits(tokio_serial::StopBits::One)
-        .flow_control(tokio_serial::FlowControl::None)
-        .open_native_async()?;
+/// Sending half of the framed transport channel, shared between proxies.
+///
+/// `None` while disconnected from the device.
+pub(crate) type SharedSink = Arc<tokio::sync::Mutex<Option<SplitSink<FramedChannel, Message>>>>;
 
-    Ok(Framed::new(stream, Codec::default()))
+/// Creates a new framed transport channel over the given transport.
+///
+/// Received frames are recorded in `counters`.
+pub(crate) fn new(transport: Box<dyn Transport>, counters: Arc<Counters>) -> FramedChannel {
+    Framed::new(transport, Codec::new(counters))
+}
+
+/// Creates a new framed transport channel by opening a transport to the
+/// given endpoint.
+///
+/// Received frames are recorded in `counters`.
+pub(crate) async fn open(
+    endpoint: &Endpoint,
+    counters: Arc<Counters>,
+) -> std::io::Result<FramedChannel> {
+    Ok(new(endpoint.open().await?, counters))
 }
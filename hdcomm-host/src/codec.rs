@@ -1,81 +1,211 @@
 /// Codec implementation for HdComm messages.
 use crate::error::CodecError;
-use bytes::{Buf, BytesMut};
-use hdcomm_core::message::Message;
-use postcard::{CobsAccumulator, FeedResult};
+use bytes::BytesMut;
+use hdcomm_core::{checksum, message::Message};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
 
+/// Counters of frames received by a `Codec`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Number of messages successfully received.
+    pub received: u64,
+    /// Number of frames dropped because they were corrupted, as their COBS
+    /// encoding was invalid or their checksum did not match.
+    pub checksum_errors: u64,
+    /// Number of frames dropped because they could not be deserialized.
+    pub deserialization_errors: u64,
+    /// Number of frames dropped because they overflowed the buffer.
+    pub overflows: u64,
+}
+
+impl FrameStats {
+    /// Total number of frames dropped.
+    pub fn dropped(&self) -> u64 {
+        self.checksum_errors + self.deserialization_errors + self.overflows
+    }
+}
+
+/// Shared frame counters, updated by a `Codec` as frames are received.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    received: AtomicU64,
+    checksum_errors: AtomicU64,
+    deserialization_errors: AtomicU64,
+    overflows: AtomicU64,
+}
+
+impl Counters {
+    /// Obtain a snapshot of the counters.
+    pub(crate) fn snapshot(&self) -> FrameStats {
+        FrameStats {
+            received: self.received.load(Ordering::Relaxed),
+            checksum_errors: self.checksum_errors.load(Ordering::Relaxed),
+            deserialization_errors: self.deserialization_errors.load(Ordering::Relaxed),
+            overflows: self.overflows.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Codec is a codec that ensures all transmitted data over the serial line is
 /// in the form of messages.
 ///
-/// `N` specifies the COBS decoder's buffer size in bytes.
+/// `N` specifies the maximum length of a received COBS frame in bytes.
 ///
-/// On transmitting, encodes all `Message`s using COBS-framed Postcard
-/// serialization.
+/// On transmitting, encodes all `Message`s using Postcard serialization,
+/// appends a checksum trailer, and frames the result using COBS.
 ///
-/// On receiving, recovers COBS frames and decodes deserializes as Postcard
-/// serialized `Message`s.
+/// On receiving, recovers COBS frames, verifies their checksum trailers and
+/// deserializes them as Postcard serialized `Message`s.
 ///
-// TODO: add better error handling?
-/// If the COBS buffer is full, existing data in the buffer is dropped.
-/// If a deserialization error occurs, the buffered data is dropped.
+/// If a frame exceeds `N` bytes, it is dropped.
+/// If a frame is corrupted or a deserialization error occurs, the frame is
+/// dropped.
 pub struct Codec<const N: usize> {
-    decoder: CobsAccumulator<N>,
+    counters: Arc<Counters>,
+}
+
+impl<const N: usize> Codec<N> {
+    /// Create a codec that records received frames in the given counters.
+    pub(crate) fn new(counters: Arc<Counters>) -> Self {
+        Self { counters }
+    }
 }
 
 impl<const N: usize> Encoder<Message> for Codec<N> {
     type Error = CodecError;
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // TODO: Make more efficient.
-        let buf = postcard::to_stdvec_cobs(&item)?;
-        dst.extend_from_slice(&buf);
+        let mut buf = postcard::to_stdvec(&item)?;
+        let trailer = checksum::checksum(&buf);
+        buf.extend_from_slice(&trailer);
+        dst.extend_from_slice(&postcard_cobs::encode_vec(&buf));
+        dst.extend_from_slice(&[0]);
         Ok(())
     }
 }
 
-/// Returns the byte distances between `p2` & `p1`, evaluated as
-/// `p2` - `p1`.
-///
-/// Error if `p2` < `p1`.
-fn offset_from(p2: *const u8, p1: *const u8) -> usize {
-    p2 as usize - p1 as usize
-}
-
 impl<const N: usize> Decoder for Codec<N> {
     type Item = Message;
     type Error = CodecError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let trim_at: usize;
-        let ret = match self.decoder.feed::<Message>(src) {
-            FeedResult::Consumed => {
-                trim_at = src.len();
-                Ok(None)
-            }
-            FeedResult::OverFull(remaining) => {
-                trim_at = offset_from(remaining.as_ptr(), src.as_ptr());
-                Err(Self::Error::FrameOverflow)
-            }
-            FeedResult::DeserError(remaining) => {
-                trim_at = offset_from(remaining.as_ptr(), src.as_ptr());
-                Err(Self::Error::Deserialization)
+        loop {
+            let end = match src.iter().position(|b| *b == 0) {
+                Some(end) => end,
+                None if src.len() > N => {
+                    // Drop the partial frame, it can never fit.
+                    src.clear();
+                    self.counters.overflows.fetch_add(1, Ordering::Relaxed);
+                    return Err(Self::Error::FrameOverflow);
+                }
+                None => return Ok(None),
+            };
+
+            let mut frame = src.split_to(end + 1);
+
+            // Skip empty frames caused by consecutive delimiters.
+            if end == 0 {
+                continue;
             }
-            FeedResult::Success { data, remaining } => {
-                trim_at = offset_from(remaining.as_ptr(), src.as_ptr());
-                Ok(Some(data))
+
+            if frame.len() > N {
+                self.counters.overflows.fetch_add(1, Ordering::Relaxed);
+                return Err(Self::Error::FrameOverflow);
             }
-        };
 
-        src.advance(trim_at);
+            // Invalid COBS encoding is a sign of corruption in transit.
+            let len = match postcard_cobs::decode_in_place(&mut frame[..end]) {
+                Ok(len) => len,
+                Err(_) => {
+                    self.counters
+                        .checksum_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Err(Self::Error::Checksum);
+                }
+            };
 
-        ret
+            let data = match checksum::verify(&frame[..len]) {
+                Some(data) => data,
+                None => {
+                    self.counters
+                        .checksum_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Err(Self::Error::Checksum);
+                }
+            };
+
+            return match postcard::from_bytes(data) {
+                Ok(message) => {
+                    self.counters.received.fetch_add(1, Ordering::Relaxed);
+                    Ok(Some(message))
+                }
+                Err(_) => {
+                    self.counters
+                        .deserialization_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    Err(Self::Error::Deserialization)
+                }
+            };
+        }
     }
 }
 
 impl<const N: usize> Default for Codec<N> {
     fn default() -> Self {
-        Self {
-            decoder: CobsAccumulator::new(),
+        Self::new(Arc::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hdcomm_core::{message, rpc, MAX_ENCODED_FRAME_LENGTH};
+
+    fn ping() -> Message {
+        Message {
+            payload: message::Payload::RPC(rpc::Message {
+                id: 1,
+                payload: rpc::Payload::PingReq(()),
+            }),
         }
     }
+
+    #[test]
+    fn decode_encoded_message() {
+        let mut codec = Codec::<MAX_ENCODED_FRAME_LENGTH>::default();
+        let mut buf = BytesMut::new();
+        codec.encode(ping(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(ping()));
+        assert_eq!(codec.counters.snapshot().received, 1);
+        assert_eq!(codec.counters.snapshot().dropped(), 0);
+    }
+
+    #[test]
+    fn corrupted_frame_rejected() {
+        let mut codec = Codec::<MAX_ENCODED_FRAME_LENGTH>::default();
+        let mut data = postcard::to_stdvec(&ping()).unwrap();
+        let trailer = checksum::checksum(&data);
+        data.extend_from_slice(&trailer);
+        data[0] ^= 0x01;
+        let mut buf = BytesMut::from(&postcard_cobs::encode_vec(&data)[..]);
+        buf.extend_from_slice(&[0]);
+
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::Checksum)));
+        assert_eq!(codec.counters.snapshot().checksum_errors, 1);
+        assert_eq!(codec.counters.snapshot().received, 0);
+    }
+
+    #[test]
+    fn invalid_cobs_frame_rejected() {
+        let mut codec = Codec::<MAX_ENCODED_FRAME_LENGTH>::default();
+        // The code byte points past the end of the frame.
+        let mut buf = BytesMut::from(&[5, 1, 0][..]);
+
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::Checksum)));
+        assert_eq!(codec.counters.snapshot().checksum_errors, 1);
+        assert_eq!(codec.counters.snapshot().deserialization_errors, 0);
+    }
 }
@@ -1,4 +1,5 @@
 /// hdcomm host-side error definitions.
+use hdcomm_core::rpc::Version;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,8 +10,14 @@ pub enum RPCError {
     /// - Elected to disconnect
     #[error("device disconnected")]
     Disconnected,
+    /// No reply was received from the remote within the allowed time.
+    #[error("timed out waiting for reply")]
+    Timeout,
     #[error("too many RPCs in flight")]
     TooManyInFlight,
+    /// The device does not support the remote procedure called.
+    #[error("procedure not supported by device")]
+    Unsupported,
     #[error("bad response")]
     BadResponse,
     #[error("codec: {0}")]
@@ -30,6 +37,30 @@ pub enum CodecError {
     FrameOverflow,
     #[error("deserialization")]
     Deserialization,
+    /// A received frame failed integrity checking and was dropped.
+    #[error("frame checksum mismatch")]
+    Checksum,
     #[error("serialization: {0}")]
     Serialization(#[from] postcard::Error),
 }
+
+/// Errors returned when performing the handshake with the device.
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    /// The device speaks an incompatible version of the protocol.
+    #[error("incompatible protocol version {0}")]
+    Incompatible(Version),
+    #[error("RPC: {0}")]
+    RPC(#[from] RPCError),
+}
+
+/// Errors returned when connecting to the device.
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    #[error("serial port: {0}")]
+    Serial(#[from] tokio_serial::Error),
+    #[error("I/O: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("handshake: {0}")]
+    Handshake(#[from] HandshakeError),
+}
@@ -0,0 +1,75 @@
+/// Protocol handshake.
+///
+/// Checks that the device speaks a compatible version of the protocol, and
+/// discovers the remote procedures it supports.
+use crate::error::{HandshakeError, RPCError};
+use crate::proxy::{Proxy, ProxyImpl};
+use crate::router::Router;
+use hdcomm_core::rpc::{HandshakeReqBody, Procedures, Version};
+use hdcomm_core::PROTOCOL_VERSION;
+
+/// Number of handshake requests sent before the device is assumed to
+/// predate the handshake.
+const ATTEMPTS: u32 = 3;
+
+/// Protocol version spoken by devices that predate the handshake.
+pub const LEGACY_VERSION: Version = Version { major: 1, minor: 0 };
+
+/// Information about the connected device, obtained from the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Protocol version spoken by the device.
+    pub version: Version,
+    /// Firmware build identifier.
+    ///
+    /// `None` for devices that predate the handshake.
+    pub build_id: Option<u32>,
+    /// Remote procedures supported by the device.
+    pub procedures: Procedures,
+}
+
+/// Performs the handshake with the device, running `router` until the reply
+/// is received.
+///
+/// Devices that never reply are assumed to predate the handshake, and to
+/// only support `Procedures::LEGACY`.
+///
+/// On success, proxies sharing `proxy`'s connection refuse to call the
+/// procedures that the device does not support with
+/// `RPCError::Unsupported`.
+pub async fn perform(router: &mut Router, proxy: &ProxyImpl) -> Result<DeviceInfo, HandshakeError> {
+    // Forget about any previously connected device, which could have
+    // predated the handshake.
+    proxy.set_device_info(None);
+
+    let mut policies = proxy.policies().clone();
+    policies.handshake.retries = ATTEMPTS - 1;
+    let proxy = proxy.with_policies(policies);
+
+    let reply = tokio::select! {
+        reply = proxy.handshake(HandshakeReqBody { version: PROTOCOL_VERSION }) => reply,
+        _ = router.run() => Err(RPCError::Disconnected),
+    };
+
+    let info = match reply {
+        Ok(reply) => DeviceInfo {
+            version: reply.version,
+            build_id: Some(reply.build_id),
+            procedures: reply.procedures,
+        },
+        Err(RPCError::Timeout) => DeviceInfo {
+            version: LEGACY_VERSION,
+            build_id: None,
+            procedures: Procedures::LEGACY,
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    if !PROTOCOL_VERSION.is_compatible(&info.version) {
+        return Err(HandshakeError::Incompatible(info.version));
+    }
+
+    proxy.set_device_info(Some(info));
+
+    Ok(info)
+}
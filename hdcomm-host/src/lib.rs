@@ -1,23 +1,122 @@
 mod channel;
 mod codec;
 pub mod error;
+pub mod handshake;
 pub mod proxy;
+pub mod recording;
 pub mod router;
+pub mod subscription;
+pub mod supervisor;
+pub mod transport;
 
+pub use codec::FrameStats;
+use error::ConnectError;
 use futures::StreamExt;
+use std::sync::Arc;
+use transport::{Endpoint, Transport};
 
+/// Connects to the device over a serial port.
+///
+/// Like all `connect` functions except `connect_with`, performs the
+/// handshake with the device, and fails if the device speaks an
+/// incompatible version of the protocol.
 pub async fn connect(
     path: &str,
     baud_rate: u32,
-) -> Result<(router::Router, proxy::ProxyImpl), tokio_serial::Error> {
-    let framed = channel::new(path, baud_rate).await?;
+) -> Result<(router::Router, proxy::ProxyImpl), ConnectError> {
+    let stream = transport::open_serial(path, baud_rate)?;
+
+    handshake_with(stream).await
+}
+
+/// Connects to the device over a TCP socket, such as one exposed by a
+/// ser2net bridge.
+pub async fn connect_tcp(
+    address: &str,
+) -> Result<(router::Router, proxy::ProxyImpl), ConnectError> {
+    connect_to(&Endpoint::Tcp(address.to_owned())).await
+}
+
+/// Connects to the device over a Unix domain socket.
+#[cfg(unix)]
+pub async fn connect_unix(
+    path: impl AsRef<std::path::Path>,
+) -> Result<(router::Router, proxy::ProxyImpl), ConnectError> {
+    connect_to(&Endpoint::Unix(path.as_ref().to_owned())).await
+}
+
+/// Connects to the device at the given endpoint.
+pub async fn connect_to(
+    endpoint: &Endpoint,
+) -> Result<(router::Router, proxy::ProxyImpl), ConnectError> {
+    handshake_with(endpoint.open().await?).await
+}
+
+/// Connects to the device over an established transport, then performs the
+/// handshake.
+async fn handshake_with<T: Transport + 'static>(
+    transport: T,
+) -> Result<(router::Router, proxy::ProxyImpl), ConnectError> {
+    let (mut router, proxy) = connect_with(transport);
+    handshake::perform(&mut router, &proxy).await?;
+
+    Ok((router, proxy))
+}
+
+/// Connects to the device over an already established transport, such as
+/// an in-process device emulator.
+///
+/// The handshake is not performed, see `handshake::perform`.
+pub fn connect_with<T: Transport + 'static>(transport: T) -> (router::Router, proxy::ProxyImpl) {
+    let counters = Arc::new(codec::Counters::default());
+    let framed = channel::new(Box::new(transport), counters.clone());
     let (sink, stream) = framed.split();
 
-    let router = router::Router::new(stream);
+    let router = router::Router::new(stream, counters);
     let proxy = proxy::ProxyImpl::new(
-        std::sync::Arc::new(tokio::sync::Mutex::new(sink)),
+        Arc::new(tokio::sync::Mutex::new(Some(sink))),
         router::RouterHandle::of(&router),
     );
 
-    Ok((router, proxy))
+    (router, proxy)
+}
+
+/// Replays a recording as if it was received from the device.
+///
+/// Messages received from the device are fed to the returned `Router` with
+/// their recorded timing, divided by `speed`, or as fast as possible if
+/// `speed` is `None`. The router reaches the end of its source at the end of
+/// the recording.
+///
+/// Requests sent through the returned proxy are discarded, and are never
+/// replied to.
+pub async fn replay(
+    path: impl AsRef<std::path::Path>,
+    speed: Option<f64>,
+) -> Result<(router::Router, proxy::ProxyImpl), std::io::Error> {
+    let reader = recording::Reader::open(path).await?;
+    let (host, device) = tokio::io::duplex(hdcomm_core::MAX_ENCODED_FRAME_LENGTH * 16);
+    tokio::spawn(recording::replay(reader, device, speed));
+
+    Ok(connect_with(host))
+}
+
+/// Creates a supervised connection to the device at the given endpoint.
+///
+/// Unlike `connect`, the transport is only opened once the returned
+/// `Supervisor` is run, and is reopened whenever the connection fails.
+/// The receiver tracks the state of the connection.
+pub fn connect_supervised(
+    endpoint: Endpoint,
+    backoff: supervisor::Backoff,
+) -> (
+    supervisor::Supervisor,
+    proxy::ProxyImpl,
+    tokio::sync::watch::Receiver<supervisor::Connection>,
+) {
+    let supervisor = supervisor::Supervisor::new(endpoint, backoff);
+    let proxy = supervisor.proxy();
+    let state = supervisor.subscribe_state();
+
+    (supervisor, proxy, state)
 }
@@ -1,11 +1,14 @@
-use crate::channel::FramedChannel;
+use crate::channel::SharedSink;
+use crate::codec::FrameStats;
 /// RPC proxy objects.
 ///
 /// Drop all proxies to terminate the device -> host side of the connection.
 use crate::error::RPCError;
+use crate::handshake::DeviceInfo;
+use crate::recording::Recorder;
 use crate::router::RouterHandle;
+use crate::subscription::{StreamEvent, Subscription};
 use async_trait::async_trait;
-use futures::stream::SplitSink;
 use futures::SinkExt;
 use hdcomm_core::message::{self, Message};
 use hdcomm_core::{
@@ -13,101 +16,295 @@ use hdcomm_core::{
     stream,
 };
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast::Receiver;
 
+/// Default time to wait for a reply to a remote procedure call.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default time to wait for a reply to remote procedure calls which change
+/// the state of the device, and are not resent on timeout.
+///
+/// A call timing out leaves the outcome of such a request unknown, so the
+/// device is given longer to reply than `DEFAULT_TIMEOUT`.
+pub const LONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Policy governing how a remote procedure call waits for its reply.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CallPolicy {
+    /// Maximum time to wait for a reply to each request sent.
+    ///
+    /// `None` to wait forever.
+    pub timeout: Option<Duration>,
+    /// Number of times a request is resent after timing out.
+    ///
+    /// Only honoured for idempotent procedures, and ignored otherwise.
+    pub retries: u32,
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_TIMEOUT),
+            retries: 0,
+        }
+    }
+}
+
 /// Macro declaring remote procedures.
+///
+/// Also declares `Policies`, which holds the default `CallPolicy` of each
+/// remote procedure.
+///
+/// - `name`: name of remote method
+/// - `request_body`: type of request body
+/// - `response_body`: type of response body
+/// - `timeout`: default time to wait for a reply.
 macro_rules! remote_procedures {
     (
-        $($name:ident, $request_body:path, $response_body:path);+
+        $($name:ident, $request_body:path, $response_body:path, $timeout:expr);+
     ) => {
         #[async_trait]
         pub trait Proxy: Clone {
         $(async fn $name(&self, body: $request_body) -> Result<$response_body, RPCError>;)+
         }
+
+        /// Default call policies for each remote procedure.
+        ///
+        /// By default, requests are never resent, and replies are waited
+        /// for `LONG_TIMEOUT` for move commands and PID parameter updates,
+        /// and `DEFAULT_TIMEOUT` for other procedures.
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct Policies {
+            $(pub $name: CallPolicy,)+
+        }
+
+        impl Default for Policies {
+            fn default() -> Self {
+                Self {
+                    $($name: CallPolicy {
+                        timeout: Some($timeout),
+                        retries: 0,
+                    },)+
+                }
+            }
+        }
     };
 }
 
 remote_procedures!(
-    ping, PingReqBody, PingRepBody;
-    move_cmd, MoveReqBody, MoveRepBody;
-    move_status, MoveStatusReqBody, MoveStatusRepBody;
-    move_cancel, MoveCancelReqBody, MoveCancelRepBody;
-    pid_param_update, PidParamUpdateReqBody, PidParamUpdateRepBody;
-    raw_teleop, RawTeleOpReqBody, RawTeleOpRepBody;
-    get_front_distance, FrontDistanceReqBody, FrontDistanceRepBody;
-    get_vin_reading, VinReadingReqBody, VinReadingRepBody
+    ping, PingReqBody, PingRepBody, DEFAULT_TIMEOUT;
+    move_cmd, MoveReqBody, MoveRepBody, LONG_TIMEOUT;
+    move_status, MoveStatusReqBody, MoveStatusRepBody, DEFAULT_TIMEOUT;
+    move_cancel, MoveCancelReqBody, MoveCancelRepBody, DEFAULT_TIMEOUT;
+    pid_param_update, PidParamUpdateReqBody, PidParamUpdateRepBody, LONG_TIMEOUT;
+    raw_teleop, RawTeleOpReqBody, RawTeleOpRepBody, DEFAULT_TIMEOUT;
+    get_front_distance, FrontDistanceReqBody, FrontDistanceRepBody, DEFAULT_TIMEOUT;
+    get_vin_reading, VinReadingReqBody, VinReadingRepBody, DEFAULT_TIMEOUT;
+    handshake, HandshakeReqBody, HandshakeRepBody, DEFAULT_TIMEOUT;
+    lease, LeaseReqBody, LeaseRepBody, DEFAULT_TIMEOUT;
+    front_distance_stream, FrontDistanceStreamReqBody, FrontDistanceStreamRepBody, DEFAULT_TIMEOUT
 );
 
+/// Timeout applied to every call made through a proxy, overriding the
+/// default timeouts of procedures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimeoutOverride {
+    /// Each procedure waits for its default timeout.
+    Default,
+    /// Wait at most the given time for each reply.
+    After(Duration),
+    /// Wait forever for each reply.
+    Never,
+}
+
 /// `ProxyImpl` implements a RPC proxy.
 #[derive(Clone)]
 pub struct ProxyImpl {
-    sink: Arc<tokio::sync::Mutex<SplitSink<FramedChannel, Message>>>,
+    sink: SharedSink,
     id: Arc<std::sync::Mutex<u16>>,
     router: RouterHandle,
+    /// Default call policies.
+    policies: Policies,
+    /// Timeout overriding the default timeout of every procedure.
+    timeout: TimeoutOverride,
 }
 
 impl ProxyImpl {
-    pub(crate) fn new(
-        sink: Arc<tokio::sync::Mutex<SplitSink<FramedChannel, Message>>>,
-        router: RouterHandle,
-    ) -> Self {
+    pub(crate) fn new(sink: SharedSink, router: RouterHandle) -> Self {
         Self {
             sink,
             id: Arc::new(std::sync::Mutex::new(0)),
             router,
+            policies: Policies::default(),
+            timeout: TimeoutOverride::Default,
+        }
+    }
+
+    /// Returns a proxy using the given default call policies.
+    pub fn with_policies(&self, policies: Policies) -> Self {
+        Self {
+            policies,
+            ..self.clone()
         }
     }
 
+    /// Returns a proxy that waits at most `timeout` for the reply to each
+    /// request, regardless of the default timeout of the procedure called.
+    ///
+    /// `None` to wait forever.
+    pub fn with_timeout(&self, timeout: Option<Duration>) -> Self {
+        Self {
+            timeout: match timeout {
+                Some(timeout) => TimeoutOverride::After(timeout),
+                None => TimeoutOverride::Never,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Default call policies used by this proxy.
+    pub fn policies(&self) -> &Policies {
+        &self.policies
+    }
+
     fn gen_id(&self) -> u16 {
         let mut id = self.id.lock().unwrap();
         *id = id.wrapping_add(1);
         *id
     }
 
+    /// Resolve the policy to use for a call, given the procedure's default.
+    fn policy(&self, default: CallPolicy, idempotent: bool) -> CallPolicy {
+        CallPolicy {
+            timeout: match self.timeout {
+                TimeoutOverride::Default => default.timeout,
+                TimeoutOverride::After(timeout) => Some(timeout),
+                TimeoutOverride::Never => None,
+            },
+            retries: if idempotent { default.retries } else { 0 },
+        }
+    }
+
+    /// Sends a single request and waits for its reply.
+    ///
+    /// The RPC listener is removed if the reply is not received.
+    async fn call(&self, payload: Payload, timeout: Option<Duration>) -> Result<Payload, RPCError> {
+        if !self.router.supports(payload.procedure()) {
+            return Err(RPCError::Unsupported);
+        }
+
+        let id = self.gen_id();
+
+        let message = Message {
+            payload: message::Payload::RPC(rpc::Message { id, payload }),
+        };
+
+        // Being unable to subscribe can only becaused by having too many
+        // RPCs in flight.
+        let receiver = self
+            .router
+            .subscribe_rpc(id)
+            .map_err(|_| RPCError::TooManyInFlight)?;
+
+        let exchange = async {
+            {
+                let mut sink = self.sink.lock().await;
+                match sink.as_mut() {
+                    Some(sink) => {
+                        self.router.record_sent(&message);
+                        sink.send(message).await?
+                    }
+                    None => return Err(RPCError::Disconnected),
+                }
+            }
+
+            // Receive errors here can only be the result of disconnection.
+            receiver.await.map_err(|_| RPCError::Disconnected)
+        };
+
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange)
+                .await
+                .unwrap_or(Err(RPCError::Timeout)),
+            None => exchange.await,
+        };
+
+        if result.is_err() {
+            self.router.unsubscribe_rpc(id);
+        }
+
+        result
+    }
+
+    /// Counters of frames received from the device.
+    ///
+    /// Includes the number of frames dropped due to corruption.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.router.frame_stats()
+    }
+
+    /// Information about the connected device.
+    ///
+    /// `None` if the handshake was not performed.
+    pub fn device_info(&self) -> Option<DeviceInfo> {
+        self.router.device_info()
+    }
+
+    /// Record information about the connected device.
+    pub(crate) fn set_device_info(&self, device: Option<DeviceInfo>) {
+        self.router.set_device_info(device)
+    }
+
+    /// Record all messages crossing the link, including those handled by the
+    /// router, with `recorder`.
+    ///
+    /// `None` to stop recording.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        self.router.set_recorder(recorder)
+    }
+
     /// Subscribe to stream messages from the device.
     pub fn subscribe(&self) -> Receiver<stream::Payload> {
         self.router.subscribe_stream()
     }
+
+    /// Subscribe to one kind of stream event from the device, such as
+    /// `stream::MoveEvent`.
+    pub fn subscribe_to<T: StreamEvent>(&self) -> Subscription<T> {
+        Subscription::new(self.subscribe())
+    }
 }
 
 /// Macro defining a remote procedure.
 ///
 /// - `name`: name of remote method
+/// - `idempotent`: whether the request may be safely resent on timeout
 /// - `request`: enum variant of RPC request
 /// - `request_body`: type of request body
 /// - `response`: enum variant of RPC response
 /// - `response_body`: type of response body.
 macro_rules! remote_procedure_impl {
     (
-        $($name:ident, $request:path, $request_body:path, $response:path, $response_body:path);+
+        $(
+            $name:ident, $idempotent:literal,
+            $request:path, $request_body:path,
+            $response:path, $response_body:path
+        );+
     ) => {
         #[async_trait]
         impl Proxy for ProxyImpl {
             $(async fn $name(&self, body: $request_body) -> Result<$response_body, RPCError> {
-                let id = self.gen_id();
+                let policy = self.policy(self.policies.$name, $idempotent);
+                let mut attempts = 0;
 
-                let message = Message {
-                    payload: message::Payload::RPC(rpc::Message {
-                        id,
-                        payload: $request(body),
-                    }),
+                let response = loop {
+                    match self.call($request(body.clone()), policy.timeout).await {
+                        Err(RPCError::Timeout) if attempts < policy.retries => attempts += 1,
+                        result => break result?,
+                    }
                 };
 
-                // Being unable to subscribe can only becaused by having too many
-                // RPCs in flight.
-                let receiver = self
-                    .router
-                    .subscribe_rpc(id)
-                    .map_err(|_| RPCError::TooManyInFlight)?;
-
-                {
-                    let mut sink = self.sink.lock().await;
-                    sink.send(message).await?;
-                }
-
-                // Receive errors here can only be the result of disconnection.
-                let response = receiver.await.map_err(|_| RPCError::Disconnected)?;
-
                 match response {
                     $response(resp_body) => Ok(resp_body),
                     _ => Err(RPCError::BadResponse),
@@ -118,12 +315,117 @@ macro_rules! remote_procedure_impl {
 }
 
 remote_procedure_impl!(
-    ping, Payload::PingReq, PingReqBody, Payload::PingRep, PingRepBody;
-    move_cmd, Payload::MoveReq, MoveReqBody, Payload::MoveRep, MoveRepBody;
-    move_status, Payload::MoveStatusReq, MoveStatusReqBody, Payload::MoveStatusRep, MoveStatusRepBody;
-    move_cancel, Payload::MoveCancelReq, MoveCancelReqBody, Payload::MoveCancelRep, MoveCancelRepBody;
-    pid_param_update, Payload::PidParamUpdateReq, PidParamUpdateReqBody, Payload::PidParamUpdateRep, PidParamUpdateRepBody;
-    raw_teleop, Payload::RawTeleOpReq, RawTeleOpReqBody, Payload::RawTeleOpRep, RawTeleOpRepBody;
-    get_front_distance, Payload::FrontDistanceReq, FrontDistanceReqBody, Payload::FrontDistanceRep, FrontDistanceRepBody;
-    get_vin_reading, Payload::VinReadingReq, VinReadingReqBody, Payload::VinReadingRep, VinReadingRepBody
+    ping, true,
+        Payload::PingReq, PingReqBody,
+        Payload::PingRep, PingRepBody;
+    move_cmd, false,
+        Payload::MoveReq, MoveReqBody,
+        Payload::MoveRep, MoveRepBody;
+    move_status, true,
+        Payload::MoveStatusReq, MoveStatusReqBody,
+        Payload::MoveStatusRep, MoveStatusRepBody;
+    move_cancel, false,
+        Payload::MoveCancelReq, MoveCancelReqBody,
+        Payload::MoveCancelRep, MoveCancelRepBody;
+    pid_param_update, false,
+        Payload::PidParamUpdateReq, PidParamUpdateReqBody,
+        Payload::PidParamUpdateRep, PidParamUpdateRepBody;
+    raw_teleop, false,
+        Payload::RawTeleOpReq, RawTeleOpReqBody,
+        Payload::RawTeleOpRep, RawTeleOpRepBody;
+    get_front_distance, true,
+        Payload::FrontDistanceReq, FrontDistanceReqBody,
+        Payload::FrontDistanceRep, FrontDistanceRepBody;
+    get_vin_reading, true,
+        Payload::VinReadingReq, VinReadingReqBody,
+        Payload::VinReadingRep, VinReadingRepBody;
+    handshake, true,
+        Payload::HandshakeReq, HandshakeReqBody,
+        Payload::HandshakeRep, HandshakeRepBody;
+    lease, true,
+        Payload::LeaseReq, LeaseReqBody,
+        Payload::LeaseRep, LeaseRepBody;
+    front_distance_stream, true,
+        Payload::FrontDistanceStreamReq, FrontDistanceStreamReqBody,
+        Payload::FrontDistanceStreamRep, FrontDistanceStreamRepBody
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use futures::StreamExt;
+    use hdcomm_core::MAX_ENCODED_FRAME_LENGTH;
+    use std::time::Instant;
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+
+    /// Device side of a link, which never replies.
+    type Device = Framed<DuplexStream, Codec<MAX_ENCODED_FRAME_LENGTH>>;
+
+    /// Time after which no reply is expected.
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn connect() -> (ProxyImpl, Device) {
+        let (host, device) = tokio::io::duplex(1024);
+        let (mut router, proxy) = crate::connect_with(host);
+        tokio::spawn(async move { router.run().await });
+        (proxy, Framed::new(device, Codec::default()))
+    }
+
+    /// Number of requests received by the device, until none is received
+    /// for `TIMEOUT`.
+    async fn requests(device: &mut Device) -> usize {
+        let mut n = 0;
+        while let Ok(Some(Ok(_))) = tokio::time::timeout(TIMEOUT, device.next()).await {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn default_timeouts() {
+        let policies = Policies::default();
+        assert_eq!(policies.ping.timeout, Some(DEFAULT_TIMEOUT));
+        assert_eq!(policies.move_cmd.timeout, Some(LONG_TIMEOUT));
+        assert_eq!(policies.pid_param_update.timeout, Some(LONG_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn call_times_out() {
+        let (proxy, mut device) = connect();
+        let started = Instant::now();
+        let result = proxy.with_timeout(Some(TIMEOUT)).ping(()).await;
+        assert!(matches!(result, Err(RPCError::Timeout)));
+        assert!(started.elapsed() >= TIMEOUT);
+        assert_eq!(requests(&mut device).await, 1);
+
+        // The listener waiting for the reply is removed.
+        assert_eq!(proxy.router.rpcs_in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_requests_resent() {
+        let (proxy, mut device) = connect();
+        let policy = CallPolicy {
+            timeout: Some(TIMEOUT),
+            retries: 2,
+        };
+        let proxy = proxy.with_policies(Policies {
+            ping: policy,
+            move_cancel: policy,
+            ..Policies::default()
+        });
+
+        assert!(matches!(proxy.ping(()).await, Err(RPCError::Timeout)));
+        assert_eq!(requests(&mut device).await, 3);
+
+        assert!(matches!(
+            proxy.move_cancel(()).await,
+            Err(RPCError::Timeout)
+        ));
+        assert_eq!(requests(&mut device).await, 1);
+
+        assert_eq!(proxy.router.rpcs_in_flight(), 0);
+    }
+}
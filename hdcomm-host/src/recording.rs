@@ -0,0 +1,241 @@
+/// Session recording and replay.
+///
+/// A recording is a sequence of `Record`s, each serialized using Postcard
+/// and framed using COBS.
+use crate::codec::Codec;
+use crate::error::CodecError;
+use bytes::BytesMut;
+use futures::FutureExt;
+use hdcomm_core::message::Message;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::codec::Encoder;
+
+/// Direction in which a message crossed the link.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the host to the device.
+    HostToDevice,
+    /// Received by the host from the device.
+    DeviceToHost,
+}
+
+/// Reason a frame received from the device was dropped.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// Frame overflowed the receive buffer.
+    FrameOverflow,
+    /// Frame could not be deserialized.
+    Deserialization,
+    /// Frame failed integrity checking.
+    Checksum,
+}
+
+impl DropReason {
+    /// Obtain the reason a frame was dropped from a codec error.
+    ///
+    /// Returns `None` for errors that are not caused by a received frame.
+    pub(crate) fn of(error: &CodecError) -> Option<Self> {
+        match error {
+            CodecError::FrameOverflow => Some(Self::FrameOverflow),
+            CodecError::Deserialization => Some(Self::Deserialization),
+            CodecError::Checksum => Some(Self::Checksum),
+            CodecError::IO(_) | CodecError::Serialization(_) => None,
+        }
+    }
+}
+
+/// Event observed on the link.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A message crossed the link.
+    Message {
+        direction: Direction,
+        message: Message,
+    },
+    /// A frame received from the device was dropped.
+    Dropped(DropReason),
+}
+
+/// A recorded event.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Host time at which the event was observed.
+    ///
+    /// In units of microseconds since the UNIX epoch.
+    pub timestamp_us: u64,
+    /// Observed event.
+    pub event: Event,
+}
+
+/// Records events observed on the link.
+///
+/// Records are written by a background task, which completes once all
+/// clones of the recorder are dropped and all records have been written.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Record>,
+}
+
+impl Recorder {
+    /// Create a recorder writing to a new file at the given path.
+    ///
+    /// The file is truncated if it already exists.
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<(Self, JoinHandle<io::Result<()>>)> {
+        Ok(Self::new(tokio::fs::File::create(path).await?))
+    }
+
+    /// Create a recorder writing to the given writer.
+    ///
+    /// Returns the recorder, and the handle of the task writing records.
+    pub fn new<W>(writer: W) -> (Self, JoinHandle<io::Result<()>>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(write_records(BufWriter::new(writer), rx));
+
+        (Self { tx }, handle)
+    }
+
+    /// Record an event observed now.
+    ///
+    /// Events are silently discarded if the writing task has failed.
+    pub(crate) fn record(&self, event: Event) {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        self.tx
+            .send(Record {
+                timestamp_us,
+                event,
+            })
+            .ok();
+    }
+}
+
+/// Writes records received from a recorder.
+///
+/// The writer is flushed whenever there are no more records pending.
+async fn write_records<W>(
+    mut writer: BufWriter<W>,
+    mut rx: mpsc::UnboundedReceiver<Record>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let record = match rx.recv().now_or_never() {
+            Some(Some(record)) => record,
+            Some(None) => break,
+            None => {
+                writer.flush().await?;
+                match rx.recv().await {
+                    Some(record) => record,
+                    None => break,
+                }
+            }
+        };
+
+        let frame = postcard::to_stdvec_cobs(&record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        writer.write_all(&frame).await?;
+    }
+
+    writer.flush().await
+}
+
+/// Reads records from a recording.
+pub struct Reader<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl Reader<tokio::io::BufReader<tokio::fs::File>> {
+    /// Open the recording at the given path.
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(tokio::io::BufReader::new(
+            tokio::fs::File::open(path).await?,
+        )))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Reader<R> {
+    /// Create a reader reading records from the given source.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Read the next record.
+    ///
+    /// Returns `None` at the end of the recording. A truncated record at the
+    /// end of the recording is ignored.
+    pub async fn next(&mut self) -> io::Result<Option<Record>> {
+        self.buf.clear();
+        self.inner.read_until(0, &mut self.buf).await?;
+
+        match self.buf.pop() {
+            Some(0) => postcard::from_bytes_cobs(&mut self.buf)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Replays messages received from the device in a recording to a transport,
+/// as the device would have sent them.
+///
+/// Data sent to the transport is discarded. The transport is shut down at
+/// the end of the recording.
+pub(crate) async fn replay<R, T>(
+    mut reader: Reader<R>,
+    transport: T,
+    speed: Option<f64>,
+) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut rx, mut tx) = tokio::io::split(transport);
+    tokio::spawn(async move { tokio::io::copy(&mut rx, &mut tokio::io::sink()).await });
+
+    let mut codec = Codec::<{ hdcomm_core::MAX_ENCODED_FRAME_LENGTH }>::default();
+    let mut buf = BytesMut::new();
+    let mut start: Option<(u64, Instant)> = None;
+
+    while let Some(record) = reader.next().await? {
+        let message = match record.event {
+            Event::Message {
+                direction: Direction::DeviceToHost,
+                message,
+            } => message,
+            _ => continue,
+        };
+
+        if let Some(speed) = speed {
+            let (first, started) = *start.get_or_insert((record.timestamp_us, Instant::now()));
+            let offset = record.timestamp_us.saturating_sub(first) as f64 / 1e6 / speed;
+            tokio::time::sleep_until(started + Duration::from_secs_f64(offset)).await;
+        }
+
+        codec
+            .encode(message, &mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        tx.write_all(&buf).await?;
+        buf.clear();
+    }
+
+    tx.shutdown().await
+}
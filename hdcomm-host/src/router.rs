@@ -1,4 +1,8 @@
 use crate::channel::FramedChannel;
+use crate::codec::{Counters, FrameStats};
+use crate::error::CodecError;
+use crate::handshake::DeviceInfo;
+use crate::recording::{Direction, DropReason, Event, Recorder};
 use futures::stream::{SplitStream, StreamExt};
 /// Router that routes responses from a framed channel to receivers.
 use hdcomm_core::{
@@ -10,12 +14,26 @@ use std::sync::{Arc, Mutex};
 use tokio::sync::{broadcast, oneshot};
 
 /// Listeners that are waiting for messages from the router.
-struct Listeners {
+pub(crate) struct Listeners {
     /// Destination for RPC reply messages received from the device.
     rpc: HashMap<u16, oneshot::Sender<rpc::Payload>>,
     /// Destination for application-level streaming messages received from the
     /// device.
     stream: broadcast::Sender<stream::Payload>,
+    /// Recorder of messages crossing the link, if any.
+    recorder: Option<Recorder>,
+    /// Information about the connected device, if the handshake was
+    /// performed.
+    device: Option<DeviceInfo>,
+}
+
+impl Listeners {
+    /// Record an event, if a recorder is installed.
+    fn record(&self, event: impl FnOnce() -> Event) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(event());
+        }
+    }
 }
 
 impl Default for Listeners {
@@ -24,6 +42,8 @@ impl Default for Listeners {
         Self {
             rpc: HashMap::new(),
             stream,
+            recorder: None,
+            device: None,
         }
     }
 }
@@ -38,31 +58,71 @@ pub struct Router {
 
     /// Message listeners.
     listeners: Arc<Mutex<Listeners>>,
+
+    /// Counters of frames received from the source.
+    counters: Arc<Counters>,
 }
 
 impl Router {
     /// Create a new router that routes messages from the given source.
-    pub(crate) fn new(incoming: SplitStream<FramedChannel>) -> Self {
+    ///
+    /// `counters` must be the counters updated by the source's codec.
+    pub(crate) fn new(incoming: SplitStream<FramedChannel>, counters: Arc<Counters>) -> Self {
+        Self::with_listeners(
+            incoming,
+            Arc::new(Mutex::new(Listeners::default())),
+            counters,
+        )
+    }
+
+    /// Create a new router that routes messages from the given source to an
+    /// existing group of listeners.
+    pub(crate) fn with_listeners(
+        incoming: SplitStream<FramedChannel>,
+        listeners: Arc<Mutex<Listeners>>,
+        counters: Arc<Counters>,
+    ) -> Self {
         Self {
             incoming,
-            listeners: Arc::new(Mutex::new(Listeners::default())),
+            listeners,
+            counters,
         }
     }
 
+    /// Counters of frames received from the device.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.counters.snapshot()
+    }
+
     /// Runs the router.
-    /// Will never exit unless cancelled.
-    pub async fn run(&mut self) {
-        loop {
-            let opt = self.incoming.next().await;
-            let res = match opt {
-                None => continue,
-                Some(res) => res,
-            };
-            let message = match res {
-                Err(_) => continue,
-                Ok(message) => message,
+    ///
+    /// Returns when the message source reaches its end, or with an error when
+    /// the message source encounters an I/O error. RPCs still waiting for
+    /// replies then fail with `RPCError::Disconnected`.
+    ///
+    /// Other errors are recoverable and are skipped.
+    pub async fn run(&mut self) -> Result<(), CodecError> {
+        let result = loop {
+            let message = match self.incoming.next().await {
+                None => break Ok(()),
+                Some(Err(CodecError::IO(e))) => break Err(CodecError::IO(e)),
+                Some(Err(e)) => {
+                    if let Some(reason) = DropReason::of(&e) {
+                        self.listeners
+                            .lock()
+                            .unwrap()
+                            .record(|| Event::Dropped(reason));
+                    }
+                    continue;
+                }
+                Some(Ok(message)) => message,
             };
 
+            self.listeners.lock().unwrap().record(|| Event::Message {
+                direction: Direction::DeviceToHost,
+                message: message.clone(),
+            });
+
             match message {
                 Message {
                     payload: message::Payload::RPC(rpc::Message { id, payload }),
@@ -77,7 +137,12 @@ impl Router {
                     self.listeners.lock().unwrap().stream.send(payload).ok();
                 }
             }
-        }
+        };
+
+        // Dropping the reply senders fails all RPCs in flight.
+        self.listeners.lock().unwrap().rpc.clear();
+
+        result
     }
 }
 
@@ -86,16 +151,30 @@ impl Router {
 pub(crate) struct RouterHandle {
     /// Group of listeners that this handle is bound to.
     listeners: Arc<Mutex<Listeners>>,
+    /// Frame counters of the router.
+    counters: Arc<Counters>,
 }
 
 impl RouterHandle {
     /// Creates a new handle referencing a created router.
     pub(crate) fn of(router: &Router) -> Self {
+        Self::new(router.listeners.clone(), router.counters.clone())
+    }
+
+    /// Creates a new handle referencing a group of listeners and frame
+    /// counters shared with routers.
+    pub(crate) fn new(listeners: Arc<Mutex<Listeners>>, counters: Arc<Counters>) -> Self {
         Self {
-            listeners: router.listeners.clone(),
+            listeners,
+            counters,
         }
     }
 
+    /// Counters of frames received by the router.
+    pub(crate) fn frame_stats(&self) -> FrameStats {
+        self.counters.snapshot()
+    }
+
     /// Subscribe to an RPC message with the given ID.
     pub(crate) fn subscribe_rpc(&self, id: u16) -> Result<oneshot::Receiver<rpc::Payload>, ()> {
         match self.listeners.lock().unwrap().rpc.entry(id) {
@@ -116,6 +195,56 @@ impl RouterHandle {
         }
     }
 
+    /// Remove the RPC listener for the given ID, if any.
+    ///
+    /// Used to clean up after RPCs that will no longer wait for a reply.
+    pub(crate) fn unsubscribe_rpc(&self, id: u16) {
+        self.listeners.lock().unwrap().rpc.remove(&id);
+    }
+
+    /// Number of RPC listeners waiting for a reply.
+    #[cfg(test)]
+    pub(crate) fn rpcs_in_flight(&self) -> usize {
+        self.listeners.lock().unwrap().rpc.len()
+    }
+
+    /// Information about the connected device, if the handshake was
+    /// performed.
+    pub(crate) fn device_info(&self) -> Option<DeviceInfo> {
+        self.listeners.lock().unwrap().device
+    }
+
+    /// Record information about the connected device.
+    pub(crate) fn set_device_info(&self, device: Option<DeviceInfo>) {
+        self.listeners.lock().unwrap().device = device;
+    }
+
+    /// Whether the connected device supports a remote procedure.
+    ///
+    /// All procedures are assumed to be supported if the handshake was not
+    /// performed.
+    pub(crate) fn supports(&self, procedure: rpc::Procedure) -> bool {
+        match self.device_info() {
+            Some(device) => device.procedures.contains(procedure),
+            None => true,
+        }
+    }
+
+    /// Install a recorder of messages crossing the link.
+    ///
+    /// `None` to stop recording.
+    pub(crate) fn set_recorder(&self, recorder: Option<Recorder>) {
+        self.listeners.lock().unwrap().recorder = recorder;
+    }
+
+    /// Record a message sent to the device, if a recorder is installed.
+    pub(crate) fn record_sent(&self, message: &Message) {
+        self.listeners.lock().unwrap().record(|| Event::Message {
+            direction: Direction::HostToDevice,
+            message: message.clone(),
+        });
+    }
+
     /// Subscribe to stream messagess.
     pub(crate) fn subscribe_stream(&self) -> broadcast::Receiver<stream::Payload> {
         self.listeners.lock().unwrap().stream.subscribe()
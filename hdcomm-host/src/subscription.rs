@@ -0,0 +1,104 @@
+/// Typed subscriptions to stream messages.
+use hdcomm_core::stream::{
+    AhrsBody, FailsafeEvent, FrontDistanceBody, MoveEvent, OdometryBody, Payload,
+};
+use std::marker::PhantomData;
+use tokio::sync::broadcast::{
+    error::{RecvError, TryRecvError},
+    Receiver,
+};
+
+/// Content of a stream message that can be subscribed to.
+pub trait StreamEvent: Sized {
+    /// Extract the event from a stream payload.
+    ///
+    /// Returns `None` if the payload does not contain this kind of event.
+    fn from_payload(payload: Payload) -> Option<Self>;
+}
+
+impl StreamEvent for AhrsBody {
+    fn from_payload(payload: Payload) -> Option<Self> {
+        match payload {
+            Payload::Ahrs(body) => Some(body),
+            _ => None,
+        }
+    }
+}
+
+impl StreamEvent for MoveEvent {
+    fn from_payload(payload: Payload) -> Option<Self> {
+        match payload {
+            Payload::Move(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl StreamEvent for OdometryBody {
+    fn from_payload(payload: Payload) -> Option<Self> {
+        match payload {
+            Payload::Odometry(body) => Some(body),
+            _ => None,
+        }
+    }
+}
+
+impl StreamEvent for FailsafeEvent {
+    fn from_payload(payload: Payload) -> Option<Self> {
+        match payload {
+            Payload::Failsafe(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl StreamEvent for FrontDistanceBody {
+    fn from_payload(payload: Payload) -> Option<Self> {
+        match payload {
+            Payload::FrontDistance(body) => Some(body),
+            _ => None,
+        }
+    }
+}
+
+/// Subscription to one kind of stream event.
+///
+/// Stream messages containing other kinds of events are skipped. They still
+/// count towards the capacity of the underlying broadcast channel, so slow
+/// subscribers can lag even when the events they are interested in are
+/// infrequent.
+pub struct Subscription<T> {
+    receiver: Receiver<Payload>,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T: StreamEvent> Subscription<T> {
+    pub(crate) fn new(receiver: Receiver<Payload>) -> Self {
+        Self {
+            receiver,
+            _event: PhantomData,
+        }
+    }
+
+    /// Receive the next event.
+    ///
+    /// See `broadcast::Receiver::recv` for the errors returned.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            if let Some(event) = T::from_payload(self.receiver.recv().await?) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Receive the next event, if one is available.
+    ///
+    /// See `broadcast::Receiver::try_recv` for the errors returned.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        loop {
+            if let Some(event) = T::from_payload(self.receiver.try_recv()?) {
+                return Ok(event);
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+/// Connection supervision.
+///
+/// Keeps the connection to the device alive by reopening the transport
+/// whenever the connection fails.
+use crate::channel::{self, SharedSink};
+use crate::codec::Counters;
+use crate::error::HandshakeError;
+use crate::handshake;
+use crate::proxy::ProxyImpl;
+use crate::router::{Listeners, Router, RouterHandle};
+use crate::transport::Endpoint;
+use futures::StreamExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// State of the connection to the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Attempting to open the transport.
+    Connecting,
+    /// Connected to the device.
+    Connected,
+    /// Connection failed or lost. Waiting before the next attempt.
+    Disconnected,
+    /// The device speaks an incompatible version of the protocol. Waiting
+    /// before the next attempt.
+    Incompatible,
+}
+
+/// State of the connection to the device, as watched by subscribers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Connection {
+    /// Connection state.
+    pub state: ConnectionState,
+    /// Number of connections established so far.
+    ///
+    /// Allows subscribers to detect reconnections even if they miss the
+    /// intermediate states.
+    pub generation: u64,
+}
+
+/// Delays between attempts to reconnect.
+///
+/// The delay starts at `initial`, and doubles after each failed attempt up
+/// to `max`. It is reset once a connection is established.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first attempt to reconnect.
+    pub initial: Duration,
+    /// Maximum delay between attempts to reconnect.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The supervisor owns the connection to the device.
+///
+/// It opens the transport, performs the handshake with the device, routes
+/// messages received from the device, and reopens the transport with backoff
+/// if an I/O error or end of stream is encountered. RPCs in flight when the
+/// connection is lost, as well as RPCs issued while disconnected, fail with
+/// `RPCError::Disconnected`.
+///
+/// Drop the `Supervisor` to terminate the connection.
+pub struct Supervisor {
+    /// Device endpoint.
+    endpoint: Endpoint,
+    /// Reconnection backoff.
+    backoff: Backoff,
+    /// Sending half of the current connection, shared with proxies.
+    sink: SharedSink,
+    /// Message listeners, persisting across connections.
+    listeners: Arc<Mutex<Listeners>>,
+    /// Frame counters, persisting across connections.
+    counters: Arc<Counters>,
+    /// Connection state.
+    state: watch::Sender<Connection>,
+    /// Number of connections established so far.
+    generation: u64,
+}
+
+impl Supervisor {
+    /// Create a new supervisor for the given endpoint.
+    pub(crate) fn new(endpoint: Endpoint, backoff: Backoff) -> Self {
+        let (state, _) = watch::channel(Connection {
+            state: ConnectionState::Disconnected,
+            generation: 0,
+        });
+        Self {
+            endpoint,
+            backoff,
+            sink: Arc::new(tokio::sync::Mutex::new(None)),
+            listeners: Arc::new(Mutex::new(Listeners::default())),
+            counters: Arc::new(Counters::default()),
+            state,
+            generation: 0,
+        }
+    }
+
+    /// Create a proxy that issues RPCs over the supervised connection.
+    pub fn proxy(&self) -> ProxyImpl {
+        ProxyImpl::new(
+            self.sink.clone(),
+            RouterHandle::new(self.listeners.clone(), self.counters.clone()),
+        )
+    }
+
+    /// Subscribe to connection state changes.
+    pub fn subscribe_state(&self) -> watch::Receiver<Connection> {
+        self.state.subscribe()
+    }
+
+    /// Notify subscribers of a connection state change.
+    fn set_state(&self, state: ConnectionState) {
+        self.state
+            .send(Connection {
+                state,
+                generation: self.generation,
+            })
+            .ok();
+    }
+
+    /// Runs the supervisor.
+    /// Will never exit unless cancelled.
+    pub async fn run(&mut self) {
+        let mut delay = self.backoff.initial;
+
+        loop {
+            self.set_state(ConnectionState::Connecting);
+            let mut state = ConnectionState::Disconnected;
+
+            if let Ok(framed) = channel::open(&self.endpoint, self.counters.clone()).await {
+                let (sink, stream) = framed.split();
+                *self.sink.lock().await = Some(sink);
+
+                let mut router =
+                    Router::with_listeners(stream, self.listeners.clone(), self.counters.clone());
+
+                match handshake::perform(&mut router, &self.proxy()).await {
+                    Ok(_) => {
+                        delay = self.backoff.initial;
+                        self.generation += 1;
+                        self.set_state(ConnectionState::Connected);
+
+                        // Both outcomes mean that the connection was lost.
+                        router.run().await.ok();
+                    }
+                    Err(HandshakeError::Incompatible(_)) => state = ConnectionState::Incompatible,
+                    Err(_) => {}
+                }
+
+                *self.sink.lock().await = None;
+            }
+
+            self.set_state(state);
+            tokio::time::sleep(delay).await;
+            delay = std::cmp::min(delay * 2, self.backoff.max);
+        }
+    }
+}
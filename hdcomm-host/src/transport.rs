@@ -0,0 +1,65 @@
+/// Transports carrying frames between the host and device.
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_serial::SerialPortBuilderExt;
+
+/// A bidirectional byte stream connected to the device.
+///
+/// Implemented for all `AsyncRead + AsyncWrite` types, allowing the host
+/// stack to run over serial ports, sockets, or in-process pipes.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// An endpoint that a transport to the device can be opened to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// Serial port using the 8N1 frame format.
+    Serial {
+        /// Serial port path.
+        path: String,
+        /// Serial port baud rate.
+        baud_rate: u32,
+    },
+    /// TCP socket, such as one exposed by a ser2net bridge.
+    ///
+    /// Contains the address of the socket in `host:port` form.
+    Tcp(String),
+    /// Unix domain socket.
+    ///
+    /// Contains the path of the socket.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Opens a transport to the endpoint.
+    pub async fn open(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(match self {
+            Self::Serial { path, baud_rate } => Box::new(open_serial(path, *baud_rate)?),
+            Self::Tcp(address) => {
+                let stream = tokio::net::TcpStream::connect(address).await?;
+                // Frames are small and latency sensitive.
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        })
+    }
+}
+
+/// Opens a serial port using the provided baud rate.
+pub(crate) fn open_serial(
+    path: &str,
+    baud_rate: u32,
+) -> Result<tokio_serial::SerialStream, tokio_serial::Error> {
+    tokio_serial::new(path, baud_rate)
+        .parity(tokio_serial::Parity::None)
+        .data_bits(tokio_serial::DataBits::Eight)
+        .stop_bits(tokio_serial::StopBits::One)
+        .flow_control(tokio_serial::FlowControl::None)
+        .open_native_async()
+}
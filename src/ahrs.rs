@@ -1,8 +1,9 @@
+use crate::calibration::Bias;
 use crate::config::Ahrs as AhrsConfig;
-use ahrs::Ahrs;
+use crate::fusion::{self, Fusion};
 /// AHRS processing module.
 use hdcomm_core::stream::AhrsBody;
-use nalgebra::{Matrix1x3, Vector3};
+use nalgebra::{UnitQuaternion, Vector3};
 
 /// Scaled AHRS sample.
 #[derive(PartialEq, Debug, Clone)]
@@ -11,11 +12,11 @@ pub struct Sample {
     ///
     /// In units of seconds.
     pub timestamp: f64,
-    /// Scaled accelerometer reading.
+    /// Scaled & bias corrected accelerometer reading.
     ///
     /// In units of ms^-2.
     pub acc: Vector3<f64>,
-    /// Scaled gyroscope reading.
+    /// Scaled & bias corrected gyroscope reading.
     ///
     /// In units of rads^-1.
     pub gyro: Vector3<f64>,
@@ -32,12 +33,12 @@ pub struct Sample {
 impl Sample {
     /// Create a scaled sample from a raw sample.
     pub fn new(config: &AhrsConfig, raw: &AhrsBody) -> Self {
-        let acc = Vector3::from_iterator(raw.acc.iter().map(|a| *a as f64 * config.acc_lsb));
-        let gyro = Vector3::from_iterator(raw.gyro.iter().map(|g| *g as f64 * config.gyro_lsb));
+        let acc = Vector3::from_iterator(raw.acc.iter().map(|a| *a as f64 * config.acc_lsb))
+            - Vector3::from_column_slice(&config.acc_bias);
+        let gyro = Vector3::from_iterator(raw.gyro.iter().map(|g| *g as f64 * config.gyro_lsb))
+            - Vector3::from_column_slice(&config.gyro_bias);
         let mag = {
-            let mut out = Matrix1x3::from_iterator(raw.mag.iter().map(|m| *m as f64));
-            out.component_mul_assign(&config.sensitivity_adjustment());
-            out *= config.mag_lsb;
+            let mut out = config.scale_mag(&raw.mag);
             out -= config.hard_iron_correction();
             out *= config.soft_iron_correction();
             out.swap((0, 0), (0, 1));
@@ -67,19 +68,145 @@ pub struct Angles {
     pub yaw: f64,
 }
 
+/// Orientation estimate produced by a filter update.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Estimate {
+    /// Estimated orientation as Euler angles.
+    pub angles: Angles,
+    /// Estimated orientation as a unit quaternion.
+    pub quaternion: UnitQuaternion<f64>,
+    /// Scaled sample the filter was updated with.
+    pub sample: Sample,
+}
+
 /// Simple radians -> degrees conversion.
 fn rad2deg(rad: f64) -> f64 {
     360.0 * (rad / (2.0 * std::f64::consts::PI))
 }
 
+/// Wrap an angle in degrees to `(-180, 180]`.
+fn wrap_degrees(angle: f64) -> f64 {
+    let wrapped = angle.rem_euclid(360.0);
+    if wrapped > 180.0 {
+        wrapped - 360.0
+    } else {
+        wrapped
+    }
+}
+
+/// Heading reading.
+///
+/// All angles are in degrees, counter-clockwise positive.
+#[derive(PartialEq, Debug, Clone)]
+pub struct HeadingReading {
+    /// Device timestamp in seconds.
+    pub timestamp: Option<f64>,
+    /// Heading relative to the reference, in `(-180, 180]`.
+    pub heading: f64,
+    /// Heading relative to the reference, accumulated across turns without
+    /// wrapping around.
+    pub unwrapped: f64,
+    /// Rate of change of the heading, in degrees per second.
+    pub rate: f64,
+}
+
+/// Tracks the heading of the robot relative to a reference.
+///
+/// The yaw of successive orientation estimates is unwrapped into a
+/// continuous angle. The reference is the heading of the first estimate,
+/// unless set otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct Heading {
+    /// Yaw of the last estimate, in degrees.
+    last_yaw: Option<f64>,
+    /// Unwrapped heading relative to the reference, in degrees.
+    unwrapped: f64,
+    /// Yaw rate of the last estimate, in degrees per second.
+    rate: f64,
+    /// Device timestamp of the last estimate, in seconds.
+    timestamp: Option<f64>,
+}
+
+impl Heading {
+    /// Update the heading with a new orientation estimate.
+    pub fn update(&mut self, estimate: &Estimate) {
+        let yaw = estimate.angles.yaw;
+        if let Some(last) = self.last_yaw {
+            self.unwrapped += wrap_degrees(yaw - last);
+        }
+        self.last_yaw = Some(yaw);
+
+        // Angular velocity is measured in the body frame.
+        let angular_velocity = estimate.quaternion * estimate.sample.gyro;
+        self.rate = rad2deg(angular_velocity.z);
+        self.timestamp = Some(estimate.sample.timestamp);
+    }
+
+    /// Re-reference the heading, such that the current heading becomes
+    /// `heading` degrees.
+    pub fn set(&mut self, heading: f64) {
+        self.unwrapped = heading;
+    }
+
+    /// Obtain the current heading.
+    pub fn reading(&self) -> HeadingReading {
+        HeadingReading {
+            timestamp: self.timestamp,
+            heading: wrap_degrees(self.unwrapped),
+            unwrapped: self.unwrapped,
+            rate: self.rate,
+        }
+    }
+}
+
+/// Gap between consecutive samples, in sample periods, above which samples
+/// are considered lost.
+const GAP_THRESHOLD_PERIODS: f64 = 1.5;
+
+/// Largest gap between consecutive samples, in sample periods, that the
+/// filter integrates over.
+///
+/// Samples following larger gaps, e.g. after the device reconnects, are
+/// integrated over a single sample period.
+const MAX_INTEGRATION_PERIODS: f64 = 5.0;
+
+/// Backward jump of the device clock, in milliseconds, above which the
+/// device is assumed to have restarted, rather than samples to have arrived
+/// out of order.
+pub(crate) const RESTART_THRESHOLD_MS: u32 = 1000;
+
+/// AHRS sample statistics.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct SampleStats {
+    /// Number of samples received.
+    pub received: u64,
+    /// Estimated number of samples lost, from gaps between the device
+    /// timestamps of consecutive samples.
+    ///
+    /// Includes samples skipped due to lagging.
+    pub lost: u64,
+    /// Number of gaps between consecutive samples.
+    pub gaps: u64,
+    /// Number of samples dropped for not being newer than the previous
+    /// sample.
+    pub out_of_order: u64,
+    /// Number of stream messages skipped because their receiver lagged
+    /// behind.
+    pub lagged: u64,
+}
+
 /// AHRS sensor fusion filter.
 pub struct Filter {
     /// Filtering configuration.
     config: AhrsConfig,
-    /// Internal filter.
-    filter: ahrs::Madgwick<f64>,
+    /// Sensor fusion algorithm.
+    filter: Box<dyn Fusion>,
     /// Last update time as measured by the device.
     last_update_time_device: Option<f64>,
+    /// Device timestamp of the last sample received, in milliseconds.
+    last_time_ms: Option<u32>,
+    /// Sample statistics.
+    stats: SampleStats,
 }
 
 impl Filter {
@@ -87,19 +214,100 @@ impl Filter {
     pub fn new(config: &AhrsConfig) -> Self {
         Self {
             config: config.clone(),
-            filter: ahrs::Madgwick::new(1.0 / config.sampling_rate, config.beta),
+            filter: fusion::new(config),
             last_update_time_device: None,
+            last_time_ms: None,
+            stats: SampleStats::default(),
         }
     }
 
     /// Update the filter with a new raw sensor reading.
-    pub fn update(&mut self, raw: &AhrsBody) {
+    ///
+    /// The filter integrates over the time elapsed since the previous
+    /// sample, as measured by the device.
+    ///
+    /// Returns the updated orientation estimate, or `None` if the sample was
+    /// dropped.
+    pub fn update(&mut self, raw: &AhrsBody) -> Option<Estimate> {
+        self.stats.received += 1;
+        let dt = self.step(raw.time_ms)?;
+
         let sample = Sample::new(&self.config, raw);
-        if let Err(e) = self.filter.update(&sample.gyro, &sample.acc, &sample.mag) {
+        if let Err(e) = self.filter.update(&sample, dt) {
             log::warn!("filter update: {} (sample dropped)", e);
-            return;
+            return None;
         }
         self.last_update_time_device = Some(sample.timestamp);
+
+        Some(Estimate {
+            angles: self.euler_angles(),
+            quaternion: self.quaternion(),
+            sample,
+        })
+    }
+
+    /// Compute the integration step for a sample with device timestamp
+    /// `time_ms`, updating the sample statistics.
+    ///
+    /// Returns `None` if the sample is not newer than the previous sample.
+    /// Sample timing is re-synchronized if the device clock jumped back,
+    /// e.g. because the device restarted.
+    fn step(&mut self, time_ms: u32) -> Option<f64> {
+        let period = 1.0 / self.config.sampling_rate;
+
+        let last = match self.last_time_ms {
+            Some(last) => last,
+            None => {
+                self.last_time_ms = Some(time_ms);
+                return Some(period);
+            }
+        };
+
+        // The device clock wraps around, so the difference is taken modulo
+        // 2^32. Differences in the upper half of the range are negative.
+        let delta = time_ms.wrapping_sub(last);
+        if delta > u32::MAX / 2 && last.wrapping_sub(time_ms) > RESTART_THRESHOLD_MS {
+            log::info!("device clock jumped back to {}ms, resynchronizing", time_ms);
+            self.last_time_ms = Some(time_ms);
+            return Some(period);
+        }
+        if delta == 0 || delta > u32::MAX / 2 {
+            log::debug!("dropped out of order AHRS sample at {}ms", time_ms);
+            self.stats.out_of_order += 1;
+            return None;
+        }
+        self.last_time_ms = Some(time_ms);
+
+        let dt = delta as f64 / 1e3;
+        if dt > GAP_THRESHOLD_PERIODS * period {
+            let lost = ((dt / period).round() as u64).saturating_sub(1);
+            log::debug!("{:.3}s gap in AHRS samples, {} lost", dt, lost);
+            self.stats.gaps += 1;
+            self.stats.lost += lost;
+        }
+
+        if dt > MAX_INTEGRATION_PERIODS * period {
+            Some(period)
+        } else {
+            Some(dt)
+        }
+    }
+
+    /// Re-synchronize sample timing with the device clock at the next
+    /// sample, e.g. once the device reconnects.
+    pub fn resync(&mut self) {
+        self.last_time_ms = None;
+    }
+
+    /// Record that `n` stream messages were skipped because their receiver
+    /// lagged behind.
+    pub fn record_lagged(&mut self, n: u64) {
+        self.stats.lagged += n;
+    }
+
+    /// Obtain the sample statistics.
+    pub fn stats(&self) -> SampleStats {
+        self.stats
     }
 
     /// Obtain the euler angles associated with the currently tracked
@@ -108,7 +316,7 @@ impl Filter {
     /// The device timestamp is also provided. May be `None` when there was
     /// no updates.
     pub fn euler_angles(&self) -> Angles {
-        let (roll, pitch, yaw) = self.filter.quat.euler_angles();
+        let (roll, pitch, yaw) = self.filter.quaternion().euler_angles();
         Angles {
             timestamp: self.last_update_time_device,
             pitch: rad2deg(pitch),
@@ -116,4 +324,83 @@ impl Filter {
             yaw: rad2deg(yaw),
         }
     }
+
+    /// Obtain the currently tracked orientation as a unit quaternion.
+    pub fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.filter.quaternion()
+    }
+
+    /// Obtain the filtering configuration.
+    pub fn config(&self) -> &AhrsConfig {
+        &self.config
+    }
+
+    /// Set the gyroscope & accelerometer biases removed from subsequent
+    /// samples.
+    pub fn set_bias(&mut self, bias: &Bias) {
+        self.config.gyro_bias.copy_from_slice(bias.gyro.as_slice());
+        self.config.acc_bias.copy_from_slice(bias.acc.as_slice());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Fusion;
+
+    fn config() -> AhrsConfig {
+        AhrsConfig {
+            acc_lsb: 1.,
+            gyro_lsb: 1.,
+            mag_lsb: 1.,
+            sampling_rate: 100.,
+            beta: 0.1,
+            fusion: Fusion::default(),
+            mag_sensitivity_adjustment: [1.; 3],
+            mag_soft_iron_correction: [1., 0., 0., 0., 1., 0., 0., 0., 1.],
+            mag_hard_iron_correction: [0.; 3],
+            gyro_bias: [0.; 3],
+            acc_bias: [0.; 3],
+            bias_calibration_window: 0.,
+        }
+    }
+
+    #[test]
+    fn step_across_clock_wraparound() {
+        let mut filter = Filter::new(&config());
+        assert_eq!(filter.step(u32::MAX - 4), Some(0.01));
+        assert_eq!(filter.step(5), Some(0.01));
+        assert_eq!(filter.step(15), Some(0.01));
+        assert_eq!(filter.stats().out_of_order, 0);
+        assert_eq!(filter.stats().gaps, 0);
+    }
+
+    #[test]
+    fn step_drops_out_of_order_samples() {
+        let mut filter = Filter::new(&config());
+        filter.step(1000);
+        assert_eq!(filter.step(1000), None);
+        assert_eq!(filter.step(990), None);
+        assert_eq!(filter.step(1010), Some(0.01));
+        assert_eq!(filter.stats().out_of_order, 2);
+    }
+
+    #[test]
+    fn step_resynchronizes_on_device_restart() {
+        let mut filter = Filter::new(&config());
+        filter.step(100_000);
+        assert_eq!(filter.step(5), Some(0.01));
+        assert_eq!(filter.step(15), Some(0.01));
+        assert_eq!(filter.stats().out_of_order, 0);
+    }
+
+    #[test]
+    fn step_limits_integration_over_gaps() {
+        let mut filter = Filter::new(&config());
+        filter.step(0);
+        assert_eq!(filter.step(30), Some(0.03));
+        assert_eq!(filter.step(1030), Some(0.01));
+        assert_eq!(filter.stats().gaps, 2);
+        assert_eq!(filter.stats().lost, 2 + 99);
+    }
 }
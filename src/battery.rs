@@ -0,0 +1,165 @@
+/// Battery monitoring.
+///
+/// VIN readings sampled by the server are smoothed, kept in a bounded
+/// history, and classified against the configured voltage thresholds.
+use crate::config::Battery as BatteryConfig;
+use hdcomm_core::rpc::VinReadingRepBody;
+use std::collections::VecDeque;
+use std::sync::RwLock;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// Number of battery readings buffered for each subscriber.
+const READING_BUFFER_SIZE: usize = 16;
+
+/// Battery level, from the smoothed voltage.
+///
+/// Levels are ordered from the healthiest to the most depleted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Above all thresholds.
+    Normal,
+    /// Below the warning threshold.
+    Warning,
+    /// Below the threshold at which new moves are refused.
+    Low,
+    /// Below the threshold at which moves in progress are cancelled.
+    Critical,
+}
+
+/// Battery reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    /// Device time of the VIN reading, in seconds.
+    pub timestamp: f64,
+    /// VIN reading, in volts.
+    pub voltage: f64,
+    /// Smoothed VIN, in volts.
+    pub smoothed: f64,
+    /// Battery level.
+    pub level: Level,
+}
+
+/// Monitor state.
+struct State {
+    /// Latest reading.
+    latest: Option<Reading>,
+    /// Smoothed readings, oldest first.
+    history: VecDeque<Reading>,
+}
+
+/// Battery monitor.
+pub struct Monitor {
+    /// Battery monitoring configuration.
+    config: BatteryConfig,
+    state: RwLock<State>,
+    /// Battery reading broadcast.
+    readings: Sender<Reading>,
+}
+
+impl Monitor {
+    /// Create a new battery monitor.
+    pub fn new(config: &BatteryConfig) -> Self {
+        Self {
+            config: config.clone(),
+            state: RwLock::new(State {
+                latest: None,
+                history: VecDeque::with_capacity(config.history_size),
+            }),
+            readings: broadcast::channel(READING_BUFFER_SIZE).0,
+        }
+    }
+
+    /// Update the monitor with a VIN reading.
+    ///
+    /// Returns the resulting battery reading.
+    pub fn update(&self, vin: &VinReadingRepBody) -> Reading {
+        let mut state = self.state.write().unwrap();
+        let timestamp = vin.time_ms as f64 / 1e3;
+        let voltage = vin.vin as f64;
+        let previous = state.latest;
+
+        let smoothed = match previous {
+            // Readings are smoothed over device time, which restarts along
+            // with the device.
+            Some(previous) if timestamp > previous.timestamp => {
+                let dt = timestamp - previous.timestamp;
+                let alpha = if self.config.time_constant > 0. {
+                    1. - (-dt / self.config.time_constant).exp()
+                } else {
+                    1.
+                };
+                previous.smoothed + alpha * (voltage - previous.smoothed)
+            }
+            _ => voltage,
+        };
+
+        let level = self.classify(smoothed, previous.map(|p| p.level).unwrap_or(Level::Normal));
+        let reading = Reading {
+            timestamp,
+            voltage,
+            smoothed,
+            level,
+        };
+
+        state.latest = Some(reading);
+        if self.config.history_size > 0 {
+            if state.history.len() >= self.config.history_size {
+                state.history.pop_front();
+            }
+            state.history.push_back(reading);
+        }
+        drop(state);
+
+        // Sending only fails when there are no subscribers.
+        let _ = self.readings.send(reading);
+
+        reading
+    }
+
+    /// Classify a smoothed voltage, given the current battery level.
+    ///
+    /// Thresholds the battery is already below are raised by the
+    /// hysteresis.
+    fn classify(&self, voltage: f64, current: Level) -> Level {
+        let thresholds = [
+            (Level::Critical, self.config.cancel_voltage),
+            (Level::Low, self.config.refuse_voltage),
+            (Level::Warning, self.config.warn_voltage),
+        ];
+
+        for (level, threshold) in thresholds.iter() {
+            let threshold = match threshold {
+                Some(threshold) if current >= *level => threshold + self.config.hysteresis,
+                Some(threshold) => *threshold,
+                None => continue,
+            };
+            if voltage < threshold {
+                return *level;
+            }
+        }
+
+        Level::Normal
+    }
+
+    /// Retrieve the latest battery reading, if any.
+    pub fn latest(&self) -> Option<Reading> {
+        self.state.read().unwrap().latest
+    }
+
+    /// Retrieve the current battery level.
+    ///
+    /// `Level::Normal` if there were no readings.
+    pub fn level(&self) -> Level {
+        self.latest().map(|r| r.level).unwrap_or(Level::Normal)
+    }
+
+    /// Retrieve the readings kept in the history, oldest first.
+    pub fn history(&self) -> Vec<Reading> {
+        self.state.read().unwrap().history.iter().copied().collect()
+    }
+
+    /// Subscribe to battery readings as they are sampled.
+    pub fn subscribe(&self) -> Receiver<Reading> {
+        self.readings.subscribe()
+    }
+}
@@ -1,32 +1,116 @@
+use clap::{App, Arg};
+use hdcomm::ahrs::Filter;
 use hdcomm::config::Config;
-use hdcomm_core::stream::Payload;
+use hdcomm_core::stream::AhrsBody;
+use hdcomm_host::recording::Recorder;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
+    let matches = App::new("ahrs_test")
+        .about("Prints the heading estimated from AHRS samples")
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("replay")
+                .help("Records the session to a file"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Replays a recorded session instead of connecting to the device"),
+        )
+        .arg(
+            Arg::with_name("fast")
+                .long("fast")
+                .requires("replay")
+                .help("Replays the recorded session as fast as possible"),
+        )
+        .get_matches();
+
     let mut config = config::Config::new();
     config.merge(config::File::new("hdcomm", config::FileFormat::Toml))?;
     let config: Config = config.try_into()?;
     log::info!("loaded configuration: {:?}", config);
 
-    let (mut router, proxy) = hdcomm_host::connect(&config.serial.name, config.serial.baud).await?;
-
-    tokio::spawn(async move { router.run().await });
-    let mut stream = proxy.subscribe();
+    let (mut router, proxy) = match matches.value_of("replay") {
+        Some(path) => {
+            let speed = if matches.is_present("fast") {
+                None
+            } else {
+                Some(1.0)
+            };
+            hdcomm_host::replay(path, speed).await?
+        }
+        None => hdcomm_host::connect_to(&config.endpoint()).await?,
+    };
+    log::info!("device: {:?}", proxy.device_info());
 
-    let mut filter = hdcomm::ahrs::Filter::new(&config.ahrs);
+    let recorder = match matches.value_of("record") {
+        Some(path) => {
+            let (recorder, handle) = Recorder::create(path).await?;
+            proxy.set_recorder(Some(recorder));
+            Some(handle)
+        }
+        None => None,
+    };
 
-    loop {
-        let msg = stream.recv().await?;
+    let mut stream = proxy.subscribe_to::<AhrsBody>();
+    let mut filter = Filter::new(&config.ahrs);
+    let process = |filter: &mut Filter, raw: AhrsBody| {
         let ts = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs_f64();
-        let Payload::Ahrs(raw) = msg;
         filter.update(&raw);
         println!("{}, {}", ts, filter.euler_angles().yaw);
+    };
+
+    let mut router = tokio::spawn(async move { router.run().await });
+
+    loop {
+        tokio::select! {
+            biased;
+            raw = stream.recv() => match raw {
+                Ok(raw) => process(&mut filter, raw),
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("skipped {} samples", n);
+                    filter.record_lagged(n);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            result = &mut router => {
+                result??;
+                break;
+            }
+        }
+    }
+
+    // Process messages routed before the router terminated.
+    loop {
+        match stream.try_recv() {
+            Ok(raw) => process(&mut filter, raw),
+            Err(TryRecvError::Lagged(n)) => {
+                log::warn!("skipped {} samples", n);
+                filter.record_lagged(n);
+            }
+            Err(_) => break,
+        }
+    }
+
+    log::info!("sample statistics: {:?}", filter.stats());
+
+    // Stop recording and wait for all records to be written.
+    proxy.set_recorder(None);
+    if let Some(handle) = recorder {
+        handle.await??;
     }
 
     Ok(())
@@ -0,0 +1,151 @@
+use clap::{App, Arg};
+use hdcomm::calibration::MagCalibration;
+use hdcomm::config::{self, Config};
+use hdcomm_core::stream::AhrsBody;
+use nalgebra::Vector3;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// Number of samples collected by default.
+///
+/// 60 seconds at the default AHRS sampling rate.
+const DEFAULT_SAMPLES: &str = "6000";
+
+/// Number of samples between progress reports.
+const PROGRESS_INTERVAL: usize = 500;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let matches = App::new("mag_calibration")
+        .about(
+            "Computes the magnetometer hard-iron & soft-iron corrections from \
+             samples collected while the robot is rotated through as many \
+             orientations as possible",
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("hdcomm.toml")
+                .help("Configuration file to read and update"),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .long("samples")
+                .value_name("COUNT")
+                .takes_value(true)
+                .default_value(DEFAULT_SAMPLES)
+                .help("Number of samples to collect"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Collects samples from a recorded session instead of the device"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Prints the corrections without updating the configuration file"),
+        )
+        .get_matches();
+
+    let path = matches.value_of("config").unwrap();
+    let count: usize = matches.value_of("samples").unwrap().parse()?;
+
+    let mut settings = ::config::Config::new();
+    settings.merge(::config::File::new(path, ::config::FileFormat::Toml))?;
+    let config: Config = settings.try_into()?;
+    log::info!("loaded configuration: {:?}", config);
+
+    let (mut router, proxy) = match matches.value_of("replay") {
+        Some(replay) => hdcomm_host::replay(replay, None).await?,
+        None => hdcomm_host::connect_to(&config.endpoint()).await?,
+    };
+    log::info!("device: {:?}", proxy.device_info());
+
+    let mut stream = proxy.subscribe_to::<AhrsBody>();
+    let mut samples = Vec::with_capacity(count);
+    let mut collect = |raw: AhrsBody| {
+        samples.push(config.ahrs.scale_mag(&raw.mag).transpose());
+        if samples.len() % PROGRESS_INTERVAL == 0 {
+            eprintln!("collected {}/{} samples", samples.len(), count);
+        }
+        samples.len() >= count
+    };
+
+    eprintln!("rotate the robot through as many orientations as possible");
+    let mut router = tokio::spawn(async move { router.run().await });
+
+    loop {
+        tokio::select! {
+            biased;
+            raw = stream.recv() => match raw {
+                Ok(raw) => if collect(raw) {
+                    break;
+                },
+                Err(RecvError::Lagged(n)) => log::warn!("skipped {} samples", n),
+                Err(RecvError::Closed) => break,
+            },
+            result = &mut router => {
+                result??;
+                // Collect samples routed before the router terminated.
+                loop {
+                    match stream.try_recv() {
+                        Ok(raw) => if collect(raw) {
+                            break;
+                        },
+                        Err(TryRecvError::Lagged(n)) => log::warn!("skipped {} samples", n),
+                        Err(_) => break,
+                    }
+                }
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    let samples: Vec<Vector3<f64>> = samples.into_iter().take(count).collect();
+    eprintln!("fitting {} samples", samples.len());
+    let calibration = MagCalibration::fit(&samples)?;
+
+    println!(
+        "field strength: {:.4e} T, residual: {:.2}%",
+        calibration.field_strength,
+        calibration.residual(&samples) * 100.
+    );
+
+    let h = &calibration.hard_iron;
+    let hard_iron = format!("[{:.4e}, {:.4e}, {:.4e}]", h.x, h.y, h.z);
+    let s = &calibration.soft_iron;
+    let soft_iron = format!(
+        "[\n    {:>7.4}, {:>7.4}, {:>7.4},\n    {:>7.4}, {:>7.4}, {:>7.4},\n    {:>7.4}, {:>7.4}, {:>7.4}\n]",
+        s[(0, 0)],
+        s[(1, 0)],
+        s[(2, 0)],
+        s[(0, 1)],
+        s[(1, 1)],
+        s[(2, 1)],
+        s[(0, 2)],
+        s[(1, 2)],
+        s[(2, 2)],
+    );
+    println!("mag_soft_iron_correction = {}", soft_iron);
+    println!("mag_hard_iron_correction = {}", hard_iron);
+
+    if matches.is_present("dry-run") {
+        return Ok(());
+    }
+
+    let document = std::fs::read_to_string(path)?;
+    let document = config::replace_value(&document, "ahrs", "mag_soft_iron_correction", &soft_iron)
+        .and_then(|d| config::replace_value(&d, "ahrs", "mag_hard_iron_correction", &hard_iron))
+        .ok_or("magnetometer corrections not found in the [ahrs] section")?;
+    std::fs::write(path, document)?;
+    println!("updated {}", path);
+
+    Ok(())
+}
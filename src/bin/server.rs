@@ -11,6 +11,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = config::Config::new();
     config.merge(config::File::new("hdcomm", config::FileFormat::Toml))?;
     let config: Config = config.try_into()?;
+    config.validate()?;
     log::info!("loaded configuration: {:?}", config);
 
     let server = ServerImpl::new(&config).await?;
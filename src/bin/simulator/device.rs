@@ -0,0 +1,418 @@
+/// Simulated device.
+use crate::motion::{self, Kinematics};
+use hdcomm::config::Config;
+use hdcomm_core::rpc::*;
+use hdcomm_core::stream::{
+    self, AhrsBody, FrontDistanceBody, MoveEvent, MoveEventKind, MoveSummary, OdometryBody,
+};
+use hdcomm_device::dispatch::Handler;
+use hdcomm_device::watchdog::Watchdog;
+use nalgebra::{Matrix1x3, Vector3};
+
+/// Wheel speed at 100% PWM duty cycle, in ms^-1.
+const MAX_WHEEL_SPEED: f64 = 0.5;
+/// Standard gravity, in ms^-2.
+const GRAVITY: f64 = 9.80665;
+/// Horizontal component of the simulated geomagnetic field, in Tesla.
+const FIELD_HORIZONTAL: f64 = 40e-6;
+/// Vertical component of the simulated geomagnetic field, in Tesla.
+///
+/// Positive upwards.
+const FIELD_VERTICAL: f64 = -10e-6;
+/// Length of the sides of the square arena the robot starts in the center
+/// of, in metres.
+const ARENA_SIZE: f64 = 2.0;
+/// Maximum range of the front distance sensor, in metres.
+const MAX_RANGE: f64 = 1.5;
+/// VIN voltage of a fully charged battery, in volts.
+const VIN_FULL: f64 = 12.6;
+/// VIN voltage of a depleted battery, in volts.
+const VIN_EMPTY: f64 = 10.5;
+/// VIN voltage drop per second of simulated time, in volts.
+const VIN_DECAY: f64 = 2e-4;
+
+/// Move being executed by the simulated device.
+struct ActiveMove {
+    body: MoveReqBody,
+    /// Simulated time the move was accepted at, in seconds.
+    start: f64,
+    /// Reference wheel position at the last update, in encoder counts.
+    position: f64,
+    /// Whether steering has settled.
+    settled: bool,
+    /// Distance travelled by the left and right wheels at the start of the
+    /// move, in metres.
+    origin: (f64, f64),
+}
+
+/// Simulated device.
+///
+/// Executes moves and raw teleop commands against a kinematic model of the
+/// robot, and synthesizes the AHRS samples its sensors would produce.
+pub struct Device {
+    config: Config,
+    /// Simulated time since the start of the device, in seconds.
+    clock: f64,
+    kinematics: Kinematics,
+    active: Option<ActiveMove>,
+    /// Move continuing the active move, started as soon as it completes.
+    queued: Option<MoveReqBody>,
+    /// Left and right wheel PWM duty cycles set through raw teleop.
+    duty: [f64; 2],
+    /// Actuator lease held by the host.
+    watchdog: Watchdog,
+    /// Interval between streamed front distance readings, in seconds.
+    ///
+    /// `0` if not streaming.
+    front_distance_interval: f64,
+    /// Simulated time the next front distance reading is streamed at, in
+    /// seconds.
+    front_distance_due: f64,
+    /// Stream payloads pending transmission.
+    pending: Vec<stream::Payload>,
+}
+
+impl Device {
+    /// Create a new simulated device from the robot's configuration.
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            clock: 0.,
+            kinematics: Kinematics {
+                x: ARENA_SIZE / 2.,
+                y: ARENA_SIZE / 2.,
+                ..Default::default()
+            },
+            active: None,
+            queued: None,
+            duty: [0.; 2],
+            watchdog: Watchdog::new(),
+            front_distance_interval: 0.,
+            front_distance_due: 0.,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Interval between simulation steps, in seconds.
+    ///
+    /// Matches the AHRS sampling interval.
+    pub fn period(&self) -> f64 {
+        1. / self.config.ahrs.sampling_rate
+    }
+
+    /// Advance the simulation by one step, returning the stream payloads
+    /// produced by the device during that step.
+    pub fn step(&mut self) -> Vec<stream::Payload> {
+        let dt = self.period();
+        self.clock += dt;
+
+        if let Some(event) = self.watchdog.poll(self.time_ms()) {
+            log::warn!("actuator lease expired at t = {:.3}s", self.clock);
+            self.move_cancel(());
+            self.duty = [0.; 2];
+            self.pending.push(event);
+        }
+
+        // Sequence number of the move whose steering settled.
+        let mut settled = None;
+        let mut completed = false;
+
+        let (dl, dr) = match self.active.as_mut() {
+            Some(active) => {
+                let body = &active.body;
+                let t = self.clock - active.start - body.steering_setup_ms as f64 / 1e3;
+                if !active.settled && t >= 0. {
+                    active.settled = true;
+                    settled = Some(body.seq);
+                }
+
+                let position = motion::position(&body.params, t);
+                let delta = (position - active.position) / self.config.model.counts_per_metre;
+                active.position = position;
+
+                let delta = if body.reverse { -delta } else { delta };
+                let other = delta * body.ratio as f64;
+                let wheels = if body.ref_left {
+                    (delta, other)
+                } else {
+                    (other, delta)
+                };
+
+                completed = self.clock - active.start >= body.time_required() as f64;
+                wheels
+            }
+            None => (
+                self.duty[0] * MAX_WHEEL_SPEED * dt,
+                self.duty[1] * MAX_WHEEL_SPEED * dt,
+            ),
+        };
+        self.kinematics.advance(dl, dr, self.config.model.w, dt);
+
+        if let Some(seq) = settled {
+            self.move_event(seq, MoveEventKind::SteeringSettled);
+        }
+        if completed {
+            log::info!("move completed at t = {:.3}s", self.clock);
+            if let Some(active) = self.active.take() {
+                let summary = self.summary(&active);
+                self.move_event(active.body.seq, MoveEventKind::Completed(summary));
+            }
+            if let Some(body) = self.queued.take() {
+                self.start(body);
+            }
+        }
+
+        let mut payloads = std::mem::take(&mut self.pending);
+        payloads.push(stream::Payload::Ahrs(self.ahrs_sample()));
+        payloads.push(stream::Payload::Odometry(self.odometry()));
+        if self.front_distance_interval > 0. && self.clock >= self.front_distance_due {
+            self.front_distance_due = self.clock + self.front_distance_interval;
+            payloads.push(stream::Payload::FrontDistance(FrontDistanceBody {
+                start_time_ms: self.time_ms(),
+                end_time_ms: self.time_ms(),
+                distance: self.front_distance().map(|d| d as f32),
+            }));
+        }
+        payloads
+    }
+
+    /// Start executing a move.
+    fn start(&mut self, body: MoveReqBody) {
+        log::info!(
+            "move started at t = {:.3}s, requiring {:.3}s",
+            self.clock,
+            body.time_required()
+        );
+        self.duty = [0.; 2];
+        let seq = body.seq;
+        self.active = Some(ActiveMove {
+            position: body.params.conditions.q0 as f64,
+            body,
+            start: self.clock,
+            settled: false,
+            origin: (self.kinematics.left, self.kinematics.right),
+        });
+        self.move_event(seq, MoveEventKind::Started);
+    }
+
+    /// Queue a lifecycle event of the move with sequence number `seq` for
+    /// transmission.
+    fn move_event(&mut self, seq: u16, kind: MoveEventKind) {
+        self.pending.push(stream::Payload::Move(MoveEvent {
+            time_ms: self.time_ms(),
+            seq,
+            kind,
+        }));
+    }
+
+    /// Summarize the state of the drive wheels for a move ending now.
+    fn summary(&self, active: &ActiveMove) -> MoveSummary {
+        let counts = |distance: f64, origin: f64| {
+            ((distance - origin) * self.config.model.counts_per_metre).round() as i32
+        };
+
+        MoveSummary {
+            encoder_counts: [
+                counts(self.kinematics.left, active.origin.0),
+                counts(self.kinematics.right, active.origin.1),
+            ],
+            // Wheels track their setpoints perfectly.
+            tracking_error: [0.; 2],
+        }
+    }
+
+    /// Report the encoder counts of the drive wheels.
+    fn odometry(&self) -> OdometryBody {
+        let counts =
+            |distance: f64| (distance * self.config.model.counts_per_metre).round() as i64 as i32;
+
+        OdometryBody {
+            encoder_counts: [counts(self.kinematics.left), counts(self.kinematics.right)],
+            time_ms: self.time_ms(),
+        }
+    }
+
+    /// Current device time in milliseconds.
+    fn time_ms(&self) -> u32 {
+        (self.clock * 1e3) as u64 as u32
+    }
+
+    /// Synthesize the raw AHRS sample for the current kinematic state.
+    fn ahrs_sample(&self) -> AhrsBody {
+        let config = &self.config.ahrs;
+        let k = &self.kinematics;
+        let quantize =
+            |v: f64, lsb: f64| (v / lsb).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+
+        let acc = [k.acceleration, k.velocity * k.yaw_rate, GRAVITY];
+        let gyro = [0., 0., k.yaw_rate];
+
+        // Geomagnetic field expressed in the robot's frame, which is what the
+        // corrected magnetometer reading should be. The corrections applied
+        // by `Sample::new` are undone to obtain the raw reading.
+        let field = Vector3::new(
+            FIELD_HORIZONTAL * k.theta.cos(),
+            -FIELD_HORIZONTAL * k.theta.sin(),
+            FIELD_VERTICAL,
+        );
+        let mut mag = Matrix1x3::new(field.y, field.x, field.z);
+        mag *= config
+            .soft_iron_correction()
+            .try_inverse()
+            .expect("soft iron correction matrix is not invertible");
+        mag += config.hard_iron_correction();
+        mag /= config.mag_lsb;
+        mag.component_div_assign(&config.sensitivity_adjustment());
+
+        AhrsBody {
+            acc: [
+                quantize(acc[0], config.acc_lsb),
+                quantize(acc[1], config.acc_lsb),
+                quantize(acc[2], config.acc_lsb),
+            ],
+            gyro: [
+                quantize(gyro[0], config.gyro_lsb),
+                quantize(gyro[1], config.gyro_lsb),
+                quantize(gyro[2], config.gyro_lsb),
+            ],
+            mag: [
+                quantize(mag[0], 1.),
+                quantize(mag[1], 1.),
+                quantize(mag[2], 1.),
+            ],
+            time_ms: self.time_ms(),
+        }
+    }
+
+    /// Distance from the robot to the arena wall in front of it.
+    fn front_distance(&self) -> Option<f64> {
+        let k = &self.kinematics;
+        let (dx, dy) = (k.theta.cos(), k.theta.sin());
+        let hit = |p: f64, d: f64| {
+            if d > 0. {
+                (ARENA_SIZE - p) / d
+            } else if d < 0. {
+                -p / d
+            } else {
+                f64::INFINITY
+            }
+        };
+        let distance = hit(k.x, dx).min(hit(k.y, dy));
+        if (0. ..=MAX_RANGE).contains(&distance) {
+            Some(distance)
+        } else {
+            None
+        }
+    }
+}
+
+impl Handler for Device {
+    fn ping(&mut self, _: PingReqBody) -> PingRepBody {
+        PingRepBody {
+            time_ms: self.time_ms(),
+        }
+    }
+
+    fn move_cmd(&mut self, body: MoveReqBody) -> MoveRepBody {
+        self.watchdog.renew(self.time_ms());
+
+        let entry_velocity = body.params.conditions.v0;
+        if entry_velocity != 0. {
+            return match &self.active {
+                Some(active)
+                    if self.queued.is_none()
+                        && active.body.params.conditions.v1 == entry_velocity
+                        && active.body.reverse == body.reverse =>
+                {
+                    log::info!("move queued at t = {:.3}s", self.clock);
+                    self.queued = Some(body);
+                    MoveRepBody::Queued
+                }
+                _ => MoveRepBody::Rejected,
+            };
+        }
+
+        if self.active.is_some() {
+            return MoveRepBody::Busy;
+        }
+
+        self.start(body);
+        MoveRepBody::Accepted
+    }
+
+    fn move_status(&mut self, _: MoveStatusReqBody) -> MoveStatusRepBody {
+        match &self.active {
+            Some(active) => {
+                let elapsed = (self.clock - active.start) as f32;
+                MoveStatusRepBody::Executing {
+                    elapsed,
+                    remaining: (active.body.time_required() - elapsed).max(0.),
+                }
+            }
+            None => MoveStatusRepBody::NoCommand,
+        }
+    }
+
+    fn move_cancel(&mut self, _: MoveCancelReqBody) -> MoveCancelRepBody {
+        self.queued = None;
+        if let Some(active) = self.active.take() {
+            log::info!("move cancelled at t = {:.3}s", self.clock);
+            let summary = self.summary(&active);
+            self.move_event(active.body.seq, MoveEventKind::Aborted(summary));
+        }
+    }
+
+    fn pid_param_update(&mut self, body: PidParamUpdateReqBody) -> PidParamUpdateRepBody {
+        if self.active.is_some() {
+            return PidParamUpdateRepBody::Busy;
+        }
+
+        log::info!("pid parameters updated: {:?}", body);
+        PidParamUpdateRepBody::Updated
+    }
+
+    fn raw_teleop(&mut self, body: RawTeleOpReqBody) -> RawTeleOpRepBody {
+        self.watchdog.renew(self.time_ms());
+
+        if self.active.is_some() {
+            return RawTeleOpRepBody::Busy;
+        }
+
+        // Steering is not modelled: the robot's motion is determined
+        // entirely by its rear wheels.
+        if let Some(duty) = body.wheel_l {
+            self.duty[0] = duty.clamp(-1., 1.) as f64;
+        }
+        if let Some(duty) = body.wheel_r {
+            self.duty[1] = duty.clamp(-1., 1.) as f64;
+        }
+        RawTeleOpRepBody::Applied
+    }
+
+    fn get_front_distance(&mut self, _: FrontDistanceReqBody) -> FrontDistanceRepBody {
+        FrontDistanceRepBody {
+            start_time_ms: self.time_ms(),
+            end_time_ms: self.time_ms(),
+            distance: self.front_distance().map(|d| d as f32),
+        }
+    }
+
+    fn get_vin_reading(&mut self, _: VinReadingReqBody) -> VinReadingRepBody {
+        VinReadingRepBody {
+            time_ms: self.time_ms(),
+            vin: (VIN_FULL - VIN_DECAY * self.clock).max(VIN_EMPTY) as f32,
+        }
+    }
+
+    fn lease(&mut self, body: LeaseReqBody) -> LeaseRepBody {
+        self.watchdog.lease(&body, self.time_ms());
+    }
+
+    fn front_distance_stream(
+        &mut self,
+        body: FrontDistanceStreamReqBody,
+    ) -> FrontDistanceStreamRepBody {
+        self.front_distance_interval = body.interval_ms as f64 / 1e3;
+        self.front_distance_due = self.clock;
+    }
+}
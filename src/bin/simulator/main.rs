@@ -0,0 +1,116 @@
+/// hdcomm device simulator.
+///
+/// Impersonates the device on a pseudo-terminal or TCP socket, executing
+/// moves in simulated time and streaming synthesized AHRS samples, so that
+/// the server can be exercised without hardware.
+mod device;
+mod motion;
+
+use clap::{App, Arg};
+use device::Device;
+use hdcomm::config::Config;
+use hdcomm_core::message::{self, Message};
+use hdcomm_core::stream;
+use hdcomm_device::dispatch::Dispatcher;
+use hdcomm_device::ENCODED_BUFFER_SIZE;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_serial::{SerialPort, SerialStream};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let matches = App::new("simulator")
+        .about("Simulates the hdcomm device")
+        .arg(
+            Arg::with_name("tcp")
+                .long("tcp")
+                .value_name("ADDRESS")
+                .takes_value(true)
+                .help("Listens on a TCP socket instead of a pseudo-terminal"),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .takes_value(true)
+                .default_value("1")
+                .help("Simulation speed relative to real time"),
+        )
+        .get_matches();
+    let speed: f64 = matches.value_of("speed").unwrap().parse()?;
+
+    let mut config = config::Config::new();
+    config.merge(config::File::new("hdcomm", config::FileFormat::Toml))?;
+    let config: Config = config.try_into()?;
+    log::info!("loaded configuration: {:?}", config);
+
+    let mut device = Device::new(&config);
+
+    match matches.value_of("tcp") {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            log::info!("listening on {}", address);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                log::info!("accepted connection from {}", peer);
+                if let Err(e) = run(stream, &mut device, speed).await {
+                    log::warn!("connection from {} failed: {}", peer, e);
+                }
+                log::info!("connection from {} closed", peer);
+            }
+        }
+        None => {
+            let (master, mut slave) = SerialStream::pair()?;
+            slave.set_exclusive(false)?;
+            // The slave is kept open for the lifetime of the simulator, so
+            // that the master does not fail while the server is not
+            // connected.
+            println!("{}", slave.name().unwrap_or_default());
+            run(master, &mut device, speed).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the simulated device over a transport until it is closed.
+async fn run<T>(transport: T, device: &mut Device, speed: f64) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (mut rx, mut tx) = tokio::io::split(transport);
+    let mut dispatcher = Dispatcher::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs_f64(device.period() / speed));
+    let mut input = [0u8; 256];
+
+    loop {
+        let mut outgoing = Vec::new();
+
+        tokio::select! {
+            read = rx.read(&mut input) => {
+                let n = read?;
+                if n == 0 {
+                    return Ok(());
+                }
+                dispatcher.feed(device, &input[..n], |reply| outgoing.push(reply));
+            }
+            _ = ticker.tick() => {
+                outgoing.extend(device.step().into_iter().map(|payload| Message {
+                    payload: message::Payload::Stream(stream::Message { payload }),
+                }));
+            }
+        }
+
+        for message in outgoing {
+            let mut buf = [0u8; ENCODED_BUFFER_SIZE];
+            let frame = hdcomm_device::frame(&message, &mut buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            tx.write_all(frame).await?;
+        }
+    }
+}
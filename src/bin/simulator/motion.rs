@@ -0,0 +1,89 @@
+/// Motion simulation.
+use s_curve::SCurveParameters;
+
+/// Evaluates the position of an S-curve profile at time `t`, in seconds
+/// since the start of the profile.
+///
+/// Positions are clamped to the start and end positions of the profile
+/// outside of its duration.
+pub fn position(p: &SCurveParameters, t: f64) -> f64 {
+    let ti = &p.time_intervals;
+    let (t_j1, t_j2) = (ti.t_j1 as f64, ti.t_j2 as f64);
+    let (t_a, t_v, t_d) = (ti.t_a as f64, ti.t_v as f64, ti.t_d as f64);
+    let total = t_a + t_v + t_d;
+    let (j_max, j_min) = (p.j_max as f64, p.j_min as f64);
+    let (a_lim_a, a_lim_d, v_lim) = (p.a_lim_a as f64, p.a_lim_d as f64, p.v_lim as f64);
+    let c = &p.conditions;
+    let (q0, q1, v0, v1) = (c.q0 as f64, c.q1 as f64, c.v0 as f64, c.v1 as f64);
+
+    if t <= 0. {
+        q0
+    } else if t <= t_j1 {
+        q0 + v0 * t + j_max * t.powi(3) / 6.
+    } else if t <= t_a - t_j1 {
+        q0 + v0 * t + a_lim_a / 6. * (3. * t.powi(2) - 3. * t_j1 * t + t_j1.powi(2))
+    } else if t <= t_a {
+        q0 + (v_lim + v0) * t_a / 2. - v_lim * (t_a - t) - j_min * (t_a - t).powi(3) / 6.
+    } else if t <= t_a + t_v {
+        q0 + (v_lim + v0) * t_a / 2. + v_lim * (t - t_a)
+    } else if t <= total - t_d + t_j2 {
+        let td = t - total + t_d;
+        q1 - (v_lim + v1) * t_d / 2. + v_lim * td - j_max * td.powi(3) / 6.
+    } else if t <= total - t_j2 {
+        let td = t - total + t_d;
+        q1 - (v_lim + v1) * t_d / 2.
+            + v_lim * td
+            + a_lim_d / 6. * (3. * td.powi(2) - 3. * t_j2 * td + t_j2.powi(2))
+    } else if t < total {
+        q1 - v1 * (total - t) - j_max * (total - t).powi(3) / 6.
+    } else {
+        q1
+    }
+}
+
+/// Kinematic state of a simulated differential drive robot.
+///
+/// The robot's x axis points forwards, and its z axis points upwards.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Kinematics {
+    /// Position in metres.
+    pub x: f64,
+    /// Position in metres.
+    pub y: f64,
+    /// Heading in radians, counter-clockwise positive.
+    pub theta: f64,
+    /// Distance travelled by the left wheel, in metres.
+    pub left: f64,
+    /// Distance travelled by the right wheel, in metres.
+    pub right: f64,
+    /// Forward velocity in ms^-1.
+    pub velocity: f64,
+    /// Forward acceleration in ms^-2.
+    pub acceleration: f64,
+    /// Yaw rate in rads^-1.
+    pub yaw_rate: f64,
+}
+
+impl Kinematics {
+    /// Advance the robot's state by moving its wheels by `dl` and `dr`
+    /// metres over `dt` seconds.
+    ///
+    /// `w` is the distance between the wheels in metres.
+    pub fn advance(&mut self, dl: f64, dr: f64, w: f64, dt: f64) {
+        let ds = (dl + dr) / 2.;
+        let dtheta = (dr - dl) / w;
+
+        // Integrate along the arc using the midpoint heading.
+        let heading = self.theta + dtheta / 2.;
+        self.x += ds * heading.cos();
+        self.y += ds * heading.sin();
+        self.theta += dtheta;
+        self.left += dl;
+        self.right += dr;
+
+        let velocity = ds / dt;
+        self.acceleration = (velocity - self.velocity) / dt;
+        self.velocity = velocity;
+        self.yaw_rate = dtheta / dt;
+    }
+}
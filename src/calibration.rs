@@ -0,0 +1,404 @@
+/// Sensor calibration.
+use crate::ahrs::RESTART_THRESHOLD_MS;
+use crate::config::Ahrs as AhrsConfig;
+use hdcomm_core::stream::AhrsBody;
+use nalgebra::{Matrix3, MatrixN, Vector3, VectorN, U9};
+use thiserror::Error;
+
+/// Minimum number of samples required to fit an ellipsoid.
+pub const MIN_MAG_SAMPLES: usize = 9;
+
+/// Standard gravity.
+///
+/// In units of ms^-2.
+pub const STANDARD_GRAVITY: f64 = 9.80665;
+
+/// Maximum standard deviation of gyroscope readings on any axis for the
+/// robot to be considered stationary.
+///
+/// In units of rads^-1.
+const MAX_STATIONARY_GYRO_STD: f64 = 0.02;
+
+/// Maximum standard deviation of accelerometer readings on any axis for the
+/// robot to be considered stationary.
+///
+/// In units of ms^-2.
+const MAX_STATIONARY_ACC_STD: f64 = 0.2;
+
+/// Calibration error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Too few samples were provided.
+    #[error(
+        "not enough samples: {0} provided, at least {} required",
+        MIN_MAG_SAMPLES
+    )]
+    NotEnoughSamples(usize),
+    /// The samples do not lie on an ellipsoid, e.g. because the sensor was
+    /// not rotated about all of its axes.
+    #[error("samples do not fit an ellipsoid")]
+    Degenerate,
+    /// The robot moved while it was required to be stationary.
+    #[error("robot moved during calibration")]
+    Moved,
+    /// Another calibration is in progress.
+    #[error("calibration already in progress")]
+    InProgress,
+}
+
+/// Magnetometer hard-iron & soft-iron calibration.
+///
+/// Corrections are expressed in the convention of `config::Ahrs`: a
+/// reading `m` is corrected as `(m - hard_iron) * soft_iron`, with `m` a
+/// row vector.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MagCalibration {
+    /// Hard-iron correction.
+    ///
+    /// In units of Tesla.
+    pub hard_iron: Vector3<f64>,
+    /// Soft-iron correction.
+    ///
+    /// Symmetric, with a determinant of 1, so that corrected readings retain
+    /// the average magnitude of uncorrected readings.
+    pub soft_iron: Matrix3<f64>,
+    /// Magnitude of corrected readings.
+    ///
+    /// In units of Tesla.
+    pub field_strength: f64,
+}
+
+impl MagCalibration {
+    /// Fit a calibration to magnetometer readings taken while the sensor was
+    /// rotated through as many orientations as possible.
+    ///
+    /// Readings are in units of Tesla, scaled but not yet corrected, as
+    /// obtained from `config::Ahrs::scale_mag`.
+    pub fn fit(samples: &[Vector3<f64>]) -> Result<Self, Error> {
+        if samples.len() < MIN_MAG_SAMPLES {
+            return Err(Error::NotEnoughSamples(samples.len()));
+        }
+
+        // Fit in normalized coordinates for numerical stability, as readings
+        // are in the order of 1e-5 Tesla.
+        let mean = samples.iter().sum::<Vector3<f64>>() / samples.len() as f64;
+        let scale = samples.iter().map(|s| (s - mean).norm()).sum::<f64>() / samples.len() as f64;
+        if scale == 0. {
+            return Err(Error::Degenerate);
+        }
+
+        // Least squares fit of the quadric
+        // ax^2 + by^2 + cz^2 + 2fyz + 2gxz + 2hxy + 2px + 2qy + 2rz = 1.
+        let mut normal = MatrixN::<f64, U9>::zeros();
+        let mut rhs = VectorN::<f64, U9>::zeros();
+        for sample in samples {
+            let v = (sample - mean) / scale;
+            let (x, y, z) = (v.x, v.y, v.z);
+            let row = VectorN::<f64, U9>::from_column_slice(&[
+                x * x,
+                y * y,
+                z * z,
+                2. * y * z,
+                2. * x * z,
+                2. * x * y,
+                2. * x,
+                2. * y,
+                2. * z,
+            ]);
+            normal += row * row.transpose();
+            rhs += row;
+        }
+        let theta = normal.cholesky().ok_or(Error::Degenerate)?.solve(&rhs);
+
+        let m = Matrix3::new(
+            theta[0], theta[5], theta[4], //
+            theta[5], theta[1], theta[3], //
+            theta[4], theta[3], theta[2],
+        );
+        let v = Vector3::new(theta[6], theta[7], theta[8]);
+        let center = -m.try_inverse().ok_or(Error::Degenerate)? * v;
+
+        // Rewrite as (x - center)^T A (x - center) = 1.
+        let k = 1. + (center.transpose() * m * center)[0];
+        let eigen = (m / k).symmetric_eigen();
+        if eigen.eigenvalues.iter().any(|l| *l <= 0. || !l.is_finite()) {
+            return Err(Error::Degenerate);
+        }
+
+        // The soft-iron correction maps the ellipsoid onto a sphere, with a
+        // radius equal to the geometric mean of the ellipsoid's radii.
+        let sqrt = Matrix3::from_diagonal(&eigen.eigenvalues.map(f64::sqrt));
+        let volume = eigen.eigenvalues.iter().product::<f64>().powf(1. / 6.);
+        let soft_iron = eigen.eigenvectors * sqrt * eigen.eigenvectors.transpose() / volume;
+
+        Ok(Self {
+            hard_iron: mean + center * scale,
+            soft_iron,
+            field_strength: scale / volume,
+        })
+    }
+
+    /// Apply the calibration to a scaled reading.
+    pub fn correct(&self, sample: &Vector3<f64>) -> Vector3<f64> {
+        self.soft_iron.transpose() * (sample - self.hard_iron)
+    }
+
+    /// Root mean square deviation of the magnitude of corrected readings
+    /// from the field strength, relative to the field strength.
+    pub fn residual(&self, samples: &[Vector3<f64>]) -> f64 {
+        let sum = samples
+            .iter()
+            .map(|s| (self.correct(s).norm() / self.field_strength - 1.).powi(2))
+            .sum::<f64>();
+        (sum / samples.len() as f64).sqrt()
+    }
+}
+
+/// Gyroscope & accelerometer biases.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Bias {
+    /// Gyroscope bias.
+    ///
+    /// In units of rads^-1.
+    pub gyro: Vector3<f64>,
+    /// Accelerometer bias.
+    ///
+    /// In units of ms^-2.
+    pub acc: Vector3<f64>,
+}
+
+/// Estimates gyroscope & accelerometer biases from samples taken while the
+/// robot is stationary and level.
+///
+/// The gyroscope should read zero, and the accelerometer should read
+/// standard gravity along the z axis. Deviations averaged over the
+/// calibration window are attributed to sensor bias.
+pub struct BiasEstimator {
+    /// AHRS configuration.
+    config: AhrsConfig,
+    /// Calibration window, in seconds of device time.
+    window: f64,
+    /// Device timestamp of the first sample, in milliseconds.
+    start: Option<u32>,
+    /// Device timestamp of the latest sample, in milliseconds.
+    last_time_ms: u32,
+    /// Number of samples accumulated.
+    count: usize,
+    /// Sum of scaled gyroscope readings.
+    gyro_sum: Vector3<f64>,
+    /// Sum of squared scaled gyroscope readings.
+    gyro_sq_sum: Vector3<f64>,
+    /// Sum of scaled accelerometer readings.
+    acc_sum: Vector3<f64>,
+    /// Sum of squared scaled accelerometer readings.
+    acc_sq_sum: Vector3<f64>,
+}
+
+impl BiasEstimator {
+    /// Create an estimator averaging samples over `window` seconds.
+    pub fn new(config: &AhrsConfig, window: f64) -> Self {
+        Self {
+            config: config.clone(),
+            window,
+            start: None,
+            last_time_ms: 0,
+            count: 0,
+            gyro_sum: Vector3::zeros(),
+            gyro_sq_sum: Vector3::zeros(),
+            acc_sum: Vector3::zeros(),
+            acc_sq_sum: Vector3::zeros(),
+        }
+    }
+
+    /// Accumulate a raw sample.
+    ///
+    /// Returns the estimated biases once the calibration window has elapsed.
+    ///
+    /// The calibration restarts from the sample if the device clock jumped
+    /// back, or forward by more than `RESTART_THRESHOLD_MS`, e.g. as the
+    /// device restarted.
+    pub fn push(&mut self, raw: &AhrsBody) -> Option<Result<Bias, Error>> {
+        if self.start.is_some() {
+            // The device clock wraps around, so the difference is taken
+            // modulo 2^32. Backward jumps wrap around to large differences.
+            let delta = raw.time_ms.wrapping_sub(self.last_time_ms);
+            if delta > RESTART_THRESHOLD_MS {
+                log::info!(
+                    "device clock jumped to {}ms, restarting bias calibration",
+                    raw.time_ms
+                );
+                *self = Self::new(&self.config, self.window);
+            }
+        }
+        self.last_time_ms = raw.time_ms;
+
+        let gyro =
+            Vector3::from_iterator(raw.gyro.iter().map(|g| *g as f64 * self.config.gyro_lsb));
+        let acc = Vector3::from_iterator(raw.acc.iter().map(|a| *a as f64 * self.config.acc_lsb));
+        self.count += 1;
+        self.gyro_sum += gyro;
+        self.gyro_sq_sum += gyro.component_mul(&gyro);
+        self.acc_sum += acc;
+        self.acc_sq_sum += acc.component_mul(&acc);
+
+        let start = *self.start.get_or_insert(raw.time_ms);
+        let elapsed = raw.time_ms.wrapping_sub(start) as f64 / 1e3;
+        if elapsed < self.window {
+            return None;
+        }
+
+        let n = self.count as f64;
+        let std = |sum: &Vector3<f64>, sq_sum: &Vector3<f64>| {
+            let mean = sum / n;
+            (sq_sum / n - mean.component_mul(&mean)).map(|v| v.max(0.).sqrt())
+        };
+        if std(&self.gyro_sum, &self.gyro_sq_sum).max() > MAX_STATIONARY_GYRO_STD
+            || std(&self.acc_sum, &self.acc_sq_sum).max() > MAX_STATIONARY_ACC_STD
+        {
+            return Some(Err(Error::Moved));
+        }
+
+        Some(Ok(Bias {
+            gyro: self.gyro_sum / n,
+            acc: self.acc_sum / n - Vector3::new(0., 0., STANDARD_GRAVITY),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// Readings of a field of `strength` Tesla taken in orientations spread
+    /// over the sphere, distorted by `soft_iron` and offset by `hard_iron`.
+    fn readings(
+        strength: f64,
+        soft_iron: &Matrix3<f64>,
+        hard_iron: &Vector3<f64>,
+    ) -> Vec<Vector3<f64>> {
+        let mut samples = Vec::new();
+        for i in 0..8 {
+            let polar = PI * (i as f64 + 0.5) / 8.;
+            for j in 0..16 {
+                let azimuth = 2. * PI * j as f64 / 16.;
+                let direction = Vector3::new(
+                    polar.sin() * azimuth.cos(),
+                    polar.sin() * azimuth.sin(),
+                    polar.cos(),
+                );
+                samples.push(soft_iron * direction * strength + hard_iron);
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn fit_ellipsoid() {
+        let soft_iron = Matrix3::new(
+            1.2, 0.1, 0.05, //
+            0.1, 0.9, -0.08, //
+            0.05, -0.08, 1.1,
+        );
+        let hard_iron = Vector3::new(2e-5, -1e-5, 3e-5);
+        let samples = readings(5e-5, &soft_iron, &hard_iron);
+
+        let calibration = MagCalibration::fit(&samples).unwrap();
+        assert!((calibration.hard_iron - hard_iron).norm() < 1e-12);
+        assert!((calibration.soft_iron.determinant() - 1.).abs() < 1e-9);
+        // The radius of the sphere is the geometric mean of the ellipsoid's
+        // radii.
+        let field_strength = 5e-5 * soft_iron.determinant().cbrt();
+        assert!((calibration.field_strength / field_strength - 1.).abs() < 1e-9);
+        assert!(calibration.residual(&samples) < 1e-9);
+    }
+
+    #[test]
+    fn fit_requires_enough_samples() {
+        let samples = readings(5e-5, &Matrix3::identity(), &Vector3::zeros());
+        assert!(matches!(
+            MagCalibration::fit(&samples[..MIN_MAG_SAMPLES - 1]),
+            Err(Error::NotEnoughSamples(n)) if n == MIN_MAG_SAMPLES - 1
+        ));
+    }
+
+    #[test]
+    fn fit_rejects_planar_readings() {
+        // Rotation about a single axis.
+        let samples: Vec<_> = (0..16)
+            .map(|i| {
+                let angle = 2. * PI * i as f64 / 16.;
+                Vector3::new(angle.cos(), angle.sin(), 0.) * 5e-5
+            })
+            .collect();
+        assert!(matches!(
+            MagCalibration::fit(&samples),
+            Err(Error::Degenerate)
+        ));
+    }
+
+    fn sample(time_ms: u32, gyro: [i16; 3]) -> AhrsBody {
+        AhrsBody {
+            acc: [0, 0, 10],
+            gyro,
+            mag: [0; 3],
+            time_ms,
+        }
+    }
+
+    fn estimator() -> BiasEstimator {
+        let config = AhrsConfig {
+            acc_lsb: 1.,
+            gyro_lsb: 0.001,
+            mag_lsb: 1.,
+            sampling_rate: 100.,
+            beta: 0.1,
+            fusion: Default::default(),
+            mag_sensitivity_adjustment: [1.; 3],
+            mag_soft_iron_correction: [1., 0., 0., 0., 1., 0., 0., 0., 1.],
+            mag_hard_iron_correction: [0.; 3],
+            gyro_bias: [0.; 3],
+            acc_bias: [0.; 3],
+            bias_calibration_window: 0.,
+        };
+        BiasEstimator::new(&config, 0.1)
+    }
+
+    #[test]
+    fn bias_estimate_across_clock_wraparound() {
+        let mut estimator = estimator();
+        let start = u32::MAX - 45;
+        for i in 0..10 {
+            let time_ms = start.wrapping_add(i * 10);
+            assert!(estimator.push(&sample(time_ms, [1, 2, 3])).is_none());
+        }
+
+        let bias = estimator.push(&sample(start.wrapping_add(100), [1, 2, 3]));
+        let bias = bias.unwrap().unwrap();
+        assert!((bias.gyro - Vector3::new(0.001, 0.002, 0.003)).norm() < 1e-12);
+        assert!((bias.acc - Vector3::new(0., 0., 10. - STANDARD_GRAVITY)).norm() < 1e-12);
+    }
+
+    #[test]
+    fn bias_calibration_restarts_on_clock_jump() {
+        for &restart in &[0, 200_000] {
+            let mut estimator = estimator();
+            // Readings while moving, before the device restarts.
+            for i in 0..5 {
+                let gyro = [i * 100, 0, 0];
+                assert!(estimator
+                    .push(&sample(100_000 + i as u32 * 10, gyro))
+                    .is_none());
+            }
+
+            for i in 0..10 {
+                assert!(estimator
+                    .push(&sample(restart + i * 10, [1, 2, 3]))
+                    .is_none());
+            }
+            let bias = estimator.push(&sample(restart + 100, [1, 2, 3]));
+            let bias = bias.unwrap().unwrap();
+            assert!((bias.gyro - Vector3::new(0.001, 0.002, 0.003)).norm() < 1e-12);
+        }
+    }
+}
@@ -1,13 +1,28 @@
 /// hdcomm configuration.
 use hdcomm_core::rpc::PidParams;
+use hdcomm_host::transport::Endpoint;
 use nalgebra::{Matrix1x3, Matrix3};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Path of the configuration file.
+pub const PATH: &str = "hdcomm.toml";
+
+#[derive(Clone, Debug, Error)]
+pub enum Error {
+    #[error("{0} must be a finite, non-negative duration")]
+    DurationInvalid(&'static str),
+    #[error("{0} must be a finite, positive duration")]
+    DurationNonPositive(&'static str),
+}
 
 /// hdcomm server configuration.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Config {
     /// gRPC server config.
     pub server: Server,
+    /// device link config.
+    pub link: Link,
     /// serial port config.
     pub serial: Serial,
     /// Robot model configuration.
@@ -16,6 +31,15 @@ pub struct Config {
     pub motion: Motion,
     /// AHRS configuration.
     pub ahrs: Ahrs,
+    /// Teleoperation configuration.
+    #[serde(default)]
+    pub teleop: Teleop,
+    /// Battery monitoring configuration.
+    #[serde(default)]
+    pub battery: Battery,
+    /// Collision guard configuration.
+    #[serde(default)]
+    pub collision: Collision,
 }
 
 /// gRPC Server configuration.
@@ -34,6 +58,46 @@ pub struct Serial {
     pub baud: u32,
 }
 
+/// Device link configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Link {
+    /// Transport used to reach the device.
+    pub transport: Transport,
+    /// Initial delay before reopening the transport after a failure.
+    ///
+    /// In units of seconds.
+    pub reconnect_delay: f64,
+    /// Maximum delay before reopening the transport after a failure.
+    ///
+    /// The delay doubles after each failed attempt up to this limit.
+    ///
+    /// In units of seconds.
+    pub reconnect_delay_max: f64,
+    /// Path of the file to record all messages crossing the link to.
+    ///
+    /// `None` to disable recording.
+    pub record: Option<String>,
+}
+
+/// Transport used to reach the device.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transport {
+    /// Serial port, as configured in the serial port configuration.
+    Serial,
+    /// TCP socket, such as one exposed by a ser2net bridge.
+    Tcp {
+        /// Socket address in `host:port` form.
+        address: String,
+    },
+    /// Unix domain socket.
+    #[cfg(unix)]
+    Unix {
+        /// Socket path.
+        path: String,
+    },
+}
+
 /// Robot model configuration.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Model {
@@ -89,6 +153,23 @@ pub struct Motion {
     pub max_velocity: f64,
     /// Time delay for steering setup (seconds).
     pub steering_setup_time: f64,
+    /// Heading error tolerated at the end of heading-corrected moves.
+    ///
+    /// In units of degrees.
+    #[serde(default = "default_heading_tolerance")]
+    pub heading_tolerance: f64,
+    /// Maximum number of corrective moves issued by a heading-corrected
+    /// move.
+    #[serde(default = "default_max_heading_corrections")]
+    pub max_heading_corrections: u32,
+}
+
+fn default_heading_tolerance() -> f64 {
+    2.
+}
+
+fn default_max_heading_corrections() -> u32 {
+    3
 }
 
 /// AHRS configuration.
@@ -108,6 +189,9 @@ pub struct Ahrs {
 
     /// Madgwick filter Beta parameter.
     pub beta: f64,
+    /// Sensor fusion algorithm.
+    #[serde(default)]
+    pub fusion: Fusion,
 
     /// Magnetometer sensitivity adjustment factor.
     pub mag_sensitivity_adjustment: [f64; 3],
@@ -122,6 +206,206 @@ pub struct Ahrs {
     ///
     /// In units of Tesla.
     pub mag_hard_iron_correction: [f64; 3],
+
+    /// Gyroscope bias, subtracted from scaled gyroscope readings.
+    ///
+    /// In units of rads^-1.
+    #[serde(default)]
+    pub gyro_bias: [f64; 3],
+    /// Accelerometer bias, subtracted from scaled accelerometer readings.
+    ///
+    /// In units of ms^-2.
+    #[serde(default)]
+    pub acc_bias: [f64; 3],
+    /// Duration of the stationary bias calibration performed when the
+    /// server starts.
+    ///
+    /// 0 to disable.
+    ///
+    /// In units of seconds.
+    #[serde(default)]
+    pub bias_calibration_window: f64,
+}
+
+/// Teleoperation configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Teleop {
+    /// Maximum interval between teleop commands before the wheels are
+    /// stopped.
+    ///
+    /// In units of seconds.
+    pub deadman_interval: f64,
+}
+
+impl Default for Teleop {
+    fn default() -> Self {
+        Self {
+            deadman_interval: 0.5,
+        }
+    }
+}
+
+/// Battery monitoring configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Battery {
+    /// Interval between VIN readings.
+    ///
+    /// 0 to disable monitoring.
+    ///
+    /// In units of seconds.
+    pub sample_interval: f64,
+    /// Time constant of the exponential smoothing applied to VIN readings.
+    ///
+    /// In units of seconds.
+    pub time_constant: f64,
+    /// Number of smoothed readings kept.
+    pub history_size: usize,
+    /// Voltage below which a warning is logged.
+    ///
+    /// In units of volts.
+    pub warn_voltage: Option<f64>,
+    /// Voltage below which new moves are refused.
+    ///
+    /// In units of volts.
+    pub refuse_voltage: Option<f64>,
+    /// Voltage below which moves in progress are cancelled, and new moves
+    /// are refused.
+    ///
+    /// In units of volts.
+    pub cancel_voltage: Option<f64>,
+    /// Voltage above a threshold that must be reached before the battery is
+    /// no longer considered below it.
+    ///
+    /// In units of volts.
+    pub hysteresis: f64,
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Self {
+            sample_interval: 1.0,
+            time_constant: 10.0,
+            history_size: 60,
+            warn_voltage: None,
+            refuse_voltage: None,
+            cancel_voltage: None,
+            hysteresis: 0.2,
+        }
+    }
+}
+
+/// Collision guard configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Collision {
+    /// Interval between front distance readings streamed by the device.
+    ///
+    /// 0 to disable the stream and the collision guard.
+    ///
+    /// In units of seconds.
+    pub sample_interval: f64,
+    /// Number of readings the obstacle distance is filtered over.
+    pub filter_window: usize,
+    /// Time taken for a move cancellation to take effect.
+    ///
+    /// In units of seconds.
+    pub latency: f64,
+    /// Distance to keep from obstacles once stopped.
+    ///
+    /// In units of metres.
+    pub stop_margin: f64,
+}
+
+impl Default for Collision {
+    fn default() -> Self {
+        Self {
+            sample_interval: 0.,
+            filter_window: 5,
+            latency: 0.1,
+            stop_margin: 0.05,
+        }
+    }
+}
+
+impl Collision {
+    /// Distance required to stop from `velocity` (in ms^-1), decelerating
+    /// at `max_accel` (in ms^-2), including the stop margin.
+    pub fn stop_distance(&self, velocity: f64, max_accel: f64) -> f64 {
+        let velocity = velocity.max(0.);
+        velocity * self.latency + velocity.powi(2) / (2. * max_accel) + self.stop_margin
+    }
+}
+
+/// Sensor fusion algorithm.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Fusion {
+    /// Madgwick filter fusing gyroscope, accelerometer & magnetometer
+    /// readings.
+    #[default]
+    Madgwick,
+    /// Madgwick filter fusing gyroscope & accelerometer readings only.
+    MadgwickImu,
+    /// Mahony filter.
+    Mahony {
+        /// Proportional gain.
+        kp: f64,
+        /// Integral gain.
+        ki: f64,
+        /// Whether magnetometer readings are fused.
+        #[serde(default)]
+        magnetometer: bool,
+    },
+    /// Gyroscope integration, with roll & pitch corrected towards the tilt
+    /// measured by the accelerometer.
+    Complementary {
+        /// Time constant of the tilt correction.
+        ///
+        /// In units of seconds.
+        time_constant: f64,
+    },
+}
+
+impl Config {
+    /// Obtain the endpoint to reach the device at.
+    pub fn endpoint(&self) -> Endpoint {
+        match &self.link.transport {
+            Transport::Serial => Endpoint::Serial {
+                path: self.serial.name.clone(),
+                baud_rate: self.serial.baud,
+            },
+            Transport::Tcp { address } => Endpoint::Tcp(address.clone()),
+            #[cfg(unix)]
+            Transport::Unix { path } => Endpoint::Unix(path.into()),
+        }
+    }
+
+    /// Check that the durations in the configuration are usable.
+    pub fn validate(&self) -> Result<(), Error> {
+        let durations = [
+            ("link.reconnect_delay", self.link.reconnect_delay),
+            ("link.reconnect_delay_max", self.link.reconnect_delay_max),
+            (
+                "ahrs.bias_calibration_window",
+                self.ahrs.bias_calibration_window,
+            ),
+            ("battery.sample_interval", self.battery.sample_interval),
+            ("collision.sample_interval", self.collision.sample_interval),
+        ];
+        for &(key, duration) in durations.iter() {
+            if !duration.is_finite() || duration < 0. {
+                return Err(Error::DurationInvalid(key));
+            }
+        }
+
+        let deadman_interval = self.teleop.deadman_interval;
+        if !deadman_interval.is_finite() || deadman_interval <= 0. {
+            return Err(Error::DurationNonPositive("teleop.deadman_interval"));
+        }
+
+        Ok(())
+    }
 }
 
 impl Ahrs {
@@ -139,4 +423,223 @@ impl Ahrs {
     pub fn sensitivity_adjustment(&self) -> Matrix1x3<f64> {
         Matrix1x3::from_column_slice(&self.mag_sensitivity_adjustment)
     }
+
+    /// Scale a raw magnetometer reading to Tesla, without applying the
+    /// hard-iron & soft-iron corrections.
+    pub fn scale_mag(&self, raw: &[i16; 3]) -> Matrix1x3<f64> {
+        let mut out = Matrix1x3::from_iterator(raw.iter().map(|m| *m as f64));
+        out.component_mul_assign(&self.sensitivity_adjustment());
+        out * self.mag_lsb
+    }
+}
+
+/// Replace the value of `key` in the `[section]` table of a TOML document.
+///
+/// Only the value is replaced, so comments and formatting elsewhere in the
+/// document are preserved. Returns `None` if the key could not be found.
+pub fn replace_value(document: &str, section: &str, key: &str, value: &str) -> Option<String> {
+    let header = format!("[{}]", section);
+    let mut in_section = false;
+    let mut offset = 0;
+
+    for line in document.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_section = trimmed == header;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+
+        let rest = match trimmed.strip_prefix(key) {
+            Some(rest) if rest.trim_start().starts_with('=') => rest,
+            _ => continue,
+        };
+        let indent = line.len() - line.trim_start().len();
+        let value_start = start + indent + key.len() + rest.find('=').unwrap() + 1;
+        let len = value_len(&document[value_start..])?;
+        let value_end = value_start + document[value_start..value_start + len].trim_end().len();
+
+        return Some(format!(
+            "{} {}{}",
+            &document[..value_start],
+            value,
+            &document[value_end..]
+        ));
+    }
+
+    None
+}
+
+/// Set the value of `key` in the `[section]` table of a TOML document.
+///
+/// Like `replace_value`, but the key is appended to the table if it is not
+/// present. Returns `None` if the table could not be found.
+pub fn set_value(document: &str, section: &str, key: &str, value: &str) -> Option<String> {
+    if let Some(document) = replace_value(document, section, key, value) {
+        return Some(document);
+    }
+
+    let header = format!("[{}]", section);
+    let mut in_section = false;
+    let mut end = None;
+    let mut offset = 0;
+
+    for line in document.split_inclusive('\n') {
+        offset += line.len();
+
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            if in_section {
+                break;
+            }
+            in_section = trimmed == header;
+            if in_section {
+                end = Some(offset);
+            }
+        } else if in_section && !trimmed.is_empty() {
+            end = Some(offset);
+        }
+    }
+
+    let end = end?;
+    let separator = if document[..end].ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+
+    Some(format!(
+        "{}{}{} = {}\n{}",
+        &document[..end],
+        separator,
+        key,
+        value,
+        &document[end..]
+    ))
+}
+
+/// Length of the TOML value at the start of `text`, excluding any trailing
+/// comment.
+///
+/// Arrays may span multiple lines. Returns `None` if an array is not closed.
+fn value_len(text: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut comment = false;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '\n' if depth == 0 => return Some(i),
+            '\n' => comment = false,
+            _ if comment => {}
+            '#' if depth == 0 => return Some(i),
+            '#' => comment = true,
+            '[' => depth += 1,
+            ']' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if depth == 0 {
+        Some(text.len())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = "\
+[ahrs]
+# Magnetometer hard-iron correction.
+mag_hard_iron_correction = [0.0, 0.0, 0.0] # Tesla
+mag_soft_iron_correction = [
+  1.0, 0.0, 0.0,
+  0.0, 1.0, 0.0,
+  0.0, 0.0, 1.0,
+]
+mag_lsb = 1.0
+
+[motion]
+mag_lsb = 2.0
+";
+
+    #[test]
+    fn replace_value_preserves_comments() {
+        let document = replace_value(DOCUMENT, "ahrs", "mag_hard_iron_correction", "[1, 2, 3]");
+        assert_eq!(
+            document.unwrap(),
+            DOCUMENT.replace("= [0.0, 0.0, 0.0] #", "= [1, 2, 3] #")
+        );
+    }
+
+    #[test]
+    fn replace_value_spanning_lines() {
+        let document = replace_value(DOCUMENT, "ahrs", "mag_soft_iron_correction", "[0]");
+        assert_eq!(
+            document.unwrap(),
+            DOCUMENT.replace(
+                "= [\n  1.0, 0.0, 0.0,\n  0.0, 1.0, 0.0,\n  0.0, 0.0, 1.0,\n]",
+                "= [0]"
+            )
+        );
+    }
+
+    #[test]
+    fn replace_value_in_section() {
+        let document = replace_value(DOCUMENT, "motion", "mag_lsb", "3.0");
+        assert_eq!(
+            document.unwrap(),
+            DOCUMENT.replace("mag_lsb = 2.0", "mag_lsb = 3.0")
+        );
+    }
+
+    #[test]
+    fn replace_value_missing_key() {
+        assert_eq!(replace_value(DOCUMENT, "ahrs", "mag", "1.0"), None);
+        assert_eq!(replace_value(DOCUMENT, "model", "mag_lsb", "1.0"), None);
+    }
+
+    #[test]
+    fn set_value_replaces_existing_key() {
+        let document = set_value(DOCUMENT, "motion", "mag_lsb", "3.0");
+        assert_eq!(
+            document.unwrap(),
+            DOCUMENT.replace("mag_lsb = 2.0", "mag_lsb = 3.0")
+        );
+    }
+
+    #[test]
+    fn set_value_appends_to_section() {
+        let document = set_value(DOCUMENT, "ahrs", "gyro_bias", "[1, 2, 3]");
+        assert_eq!(
+            document.unwrap(),
+            DOCUMENT.replace("mag_lsb = 1.0\n", "mag_lsb = 1.0\ngyro_bias = [1, 2, 3]\n")
+        );
+    }
+
+    #[test]
+    fn set_value_appends_to_last_section() {
+        let document = set_value("[motion]\nmax_jerk = 1.0", "motion", "max_accel", "2.0");
+        assert_eq!(
+            document.unwrap(),
+            "[motion]\nmax_jerk = 1.0\nmax_accel = 2.0\n"
+        );
+    }
+
+    #[test]
+    fn set_value_missing_section() {
+        assert_eq!(set_value(DOCUMENT, "model", "mag_lsb", "1.0"), None);
+    }
 }
@@ -0,0 +1,194 @@
+/// Sensor fusion algorithms.
+use crate::ahrs::Sample;
+use crate::config::{Ahrs as AhrsConfig, Fusion as FusionConfig};
+use ahrs::Ahrs;
+use nalgebra::{UnitQuaternion, Vector3};
+
+/// Sensor fusion algorithm, estimating the orientation of the robot from
+/// scaled AHRS samples.
+pub trait Fusion: Send + Sync {
+    /// Update the orientation estimate with a new sample, taken `dt`
+    /// seconds after the previous one.
+    fn update(&mut self, sample: &Sample, dt: f64) -> Result<(), String>;
+
+    /// Obtain the current orientation estimate.
+    fn quaternion(&self) -> UnitQuaternion<f64>;
+}
+
+/// Create the sensor fusion algorithm selected in the configuration.
+pub fn new(config: &AhrsConfig) -> Box<dyn Fusion> {
+    let sample_period = 1.0 / config.sampling_rate;
+
+    match &config.fusion {
+        FusionConfig::Madgwick => Box::new(Madgwick {
+            filter: ahrs::Madgwick::new(sample_period, config.beta),
+            sample_period,
+            beta: config.beta,
+            magnetometer: true,
+        }),
+        FusionConfig::MadgwickImu => Box::new(Madgwick {
+            filter: ahrs::Madgwick::new(sample_period, config.beta),
+            sample_period,
+            beta: config.beta,
+            magnetometer: false,
+        }),
+        FusionConfig::Mahony {
+            kp,
+            ki,
+            magnetometer,
+        } => Box::new(Mahony {
+            filter: ahrs::Mahony::new(sample_period, *kp, *ki),
+            sample_period,
+            magnetometer: *magnetometer,
+        }),
+        FusionConfig::Complementary { time_constant } => {
+            Box::new(Complementary::new(*time_constant))
+        }
+    }
+}
+
+/// Madgwick filter.
+struct Madgwick {
+    /// Internal filter.
+    filter: ahrs::Madgwick<f64>,
+    /// Sample period of the internal filter, in seconds.
+    sample_period: f64,
+    /// Filter gain.
+    beta: f64,
+    /// Whether magnetometer readings are fused.
+    magnetometer: bool,
+}
+
+impl Fusion for Madgwick {
+    fn update(&mut self, sample: &Sample, dt: f64) -> Result<(), String> {
+        if dt != self.sample_period {
+            self.filter = ahrs::Madgwick::new_with_quat(dt, self.beta, self.filter.quat);
+            self.sample_period = dt;
+        }
+
+        let result = if self.magnetometer {
+            self.filter.update(&sample.gyro, &sample.acc, &sample.mag)
+        } else {
+            self.filter.update_imu(&sample.gyro, &sample.acc)
+        };
+        result.map(|_| ()).map_err(str::to_owned)
+    }
+
+    fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.filter.quat
+    }
+}
+
+/// Mahony filter.
+///
+/// The internal filter keeps the nominal sample period, as rebuilding it
+/// would reset its integral term. Gyroscope readings are scaled by the
+/// ratio of the actual to the nominal sample period instead, so that
+/// rotations are integrated over the actual sample period.
+struct Mahony {
+    /// Internal filter.
+    filter: ahrs::Mahony<f64>,
+    /// Sample period of the internal filter, in seconds.
+    sample_period: f64,
+    /// Whether magnetometer readings are fused.
+    magnetometer: bool,
+}
+
+impl Fusion for Mahony {
+    fn update(&mut self, sample: &Sample, dt: f64) -> Result<(), String> {
+        let gyro = sample.gyro * (dt / self.sample_period);
+
+        let result = if self.magnetometer {
+            self.filter.update(&gyro, &sample.acc, &sample.mag)
+        } else {
+            self.filter.update_imu(&gyro, &sample.acc)
+        };
+        result.map(|_| ()).map_err(str::to_owned)
+    }
+
+    fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.filter.quat
+    }
+}
+
+/// Complementary filter.
+///
+/// Integrates gyroscope readings, and slowly corrects roll & pitch towards
+/// the tilt measured by the accelerometer. Yaw is obtained from gyroscope
+/// integration alone.
+pub struct Complementary {
+    /// Time constant of the tilt correction, in seconds.
+    time_constant: f64,
+    /// Current orientation estimate.
+    quat: UnitQuaternion<f64>,
+}
+
+impl Complementary {
+    /// Create a complementary filter.
+    ///
+    /// `time_constant` is the time constant of the tilt correction, in
+    /// seconds.
+    pub fn new(time_constant: f64) -> Self {
+        Self {
+            time_constant,
+            quat: UnitQuaternion::identity(),
+        }
+    }
+}
+
+impl Fusion for Complementary {
+    fn update(&mut self, sample: &Sample, dt: f64) -> Result<(), String> {
+        // Angular rates are measured in the body frame.
+        let predicted = self.quat * UnitQuaternion::from_scaled_axis(sample.gyro * dt);
+
+        let measured = sample
+            .acc
+            .try_normalize(0.)
+            .ok_or_else(|| "accelerometer norm is zero".to_owned())?;
+        let expected = predicted.inverse() * Vector3::z();
+
+        // Rotate the expected direction of gravity in the body frame towards
+        // the measured direction. The rotation axis is horizontal, so yaw is
+        // left unchanged.
+        let gain = dt / (self.time_constant + dt);
+        let correction = UnitQuaternion::scaled_rotation_between(&expected, &measured, gain);
+        self.quat = match correction {
+            Some(correction) => predicted * correction.inverse(),
+            None => predicted,
+        };
+
+        Ok(())
+    }
+
+    fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.quat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mahony_integrates_over_sample_period() {
+        let mut mahony = Mahony {
+            filter: ahrs::Mahony::new(0.01, 0.5, 0.1),
+            sample_period: 0.01,
+            magnetometer: false,
+        };
+        let sample = Sample {
+            timestamp: 0.,
+            acc: Vector3::new(0., 0., 9.81),
+            gyro: Vector3::new(0., 0., 0.5),
+            mag: Vector3::zeros(),
+        };
+
+        // Samples arrive 20% late.
+        for _ in 0..200 {
+            mahony.update(&sample, 0.012).unwrap();
+        }
+        // 2.4s at 0.5rads^-1.
+        let (_, _, yaw) = mahony.quaternion().euler_angles();
+        assert!((yaw - 1.2).abs() < 1e-3, "yaw {}", yaw);
+    }
+}
@@ -1,8 +1,16 @@
 pub mod ahrs;
+pub mod battery;
+pub mod calibration;
 /// Host to device communication proxy.
 ///
 /// Provides a gRPC interface for the hdcomm protocol.
 pub mod config;
+pub mod fusion;
 pub mod model;
+pub mod moves;
+pub mod obstacle;
+pub mod odometry;
+pub mod path;
 pub mod server;
 pub mod stream;
+pub mod teleop;
@@ -1,6 +1,6 @@
 /// Robot model and move generator.
 use crate::config::{Model as ModelConfig, Motion as MotionConfig};
-use hdcomm_core::rpc::MoveReqBody;
+use hdcomm_core::rpc::{MoveReqBody, PidParams};
 use s_curve::{SCurveConstraints, SCurveInput, SCurveParameters, SCurveStartConditions};
 use thiserror::Error;
 
@@ -10,10 +10,21 @@ pub enum Error {
     RadiusNotSupported,
     #[error("motion profile limits must be positive")]
     ProfileLimitsNonPositive,
+    #[error("steering setup time must not be negative")]
+    SteeringSetupTimeNegative,
+    #[error("PID gains & limits must be finite and non-negative")]
+    PidParamsInvalid,
+    #[error("non-zero entry & exit velocities are only supported for straight moves")]
+    VelocityNotSupported,
+    #[error("entry & exit velocities must be within the motion profile limits")]
+    VelocityOutOfRange,
+    #[error("move too short to reach the exit velocity")]
+    VelocityInfeasible,
 }
 
 /// Model models the nanocar robot and generates actual physical moves from
 /// abstract move commands.
+#[derive(Clone)]
 pub struct Model {
     /// Robot model configuration.
     pub model: ModelConfig,
@@ -37,6 +48,24 @@ impl Model {
     /// `steering_setup_ms` will be clamped to `0xffff` if the value specified
     /// in `steering_setup_time` exceeds `0xffff` milliseconds.
     pub fn generate_move(&self, radius: i32, distance: f64) -> Result<MoveReqBody, Error> {
+        self.generate_blended_move(radius, distance, 0., 0.)
+    }
+
+    /// Generate a move request as for `generate_move`, starting at
+    /// `entry_velocity` and ending at `exit_velocity` (both in ms^-1, in the
+    /// direction of travel).
+    ///
+    /// Non-zero velocities are only supported for straight moves, allowing
+    /// consecutive straight moves in the same direction to be chained
+    /// without stopping. Moves entered at a non-zero velocity skip steering
+    /// setup, as the robot is already moving straight.
+    pub fn generate_blended_move(
+        &self,
+        radius: i32,
+        distance: f64,
+        entry_velocity: f64,
+        exit_velocity: f64,
+    ) -> Result<MoveReqBody, Error> {
         // True for right turns, false for left turns.
         let ref_left = radius <= 0;
         let left_turn = !ref_left;
@@ -77,8 +106,21 @@ impl Model {
             }
         };
 
+        let blended = entry_velocity != 0. || exit_velocity != 0.;
+        if blended && !straight {
+            return Err(Error::VelocityNotSupported);
+        }
+        let velocity_range = 0. ..=self.motion.max_velocity;
+        if !velocity_range.contains(&entry_velocity) || !velocity_range.contains(&exit_velocity) {
+            return Err(Error::VelocityOutOfRange);
+        }
+
         // This is a saturating conversion to u16.
-        let steering_setup_ms = (self.motion.steering_setup_time * 1e3) as u16;
+        let steering_setup_ms = if entry_velocity != 0. {
+            0
+        } else {
+            (self.motion.steering_setup_time * 1e3) as u16
+        };
 
         let constraints = SCurveConstraints {
             max_acceleration: (self.motion.max_accel * self.model.counts_per_metre) as f32,
@@ -89,14 +131,17 @@ impl Model {
         let start_conditions = SCurveStartConditions {
             q0: 0.,
             q1: ref_ticks as f32,
-            v0: 0.,
-            v1: 0.,
+            v0: (entry_velocity * self.model.counts_per_metre) as f32,
+            v1: (exit_velocity * self.model.counts_per_metre) as f32,
         };
 
         let input = SCurveInput {
             constraints,
             start_conditions,
         };
+        if blended && !input.is_trajectory_feasible() {
+            return Err(Error::VelocityInfeasible);
+        }
 
         let time_intervals = input.calc_intervals();
         let params = SCurveParameters::new(&time_intervals, &input);
@@ -108,9 +153,52 @@ impl Model {
             steering: steering as f32,
             steering_setup_ms,
             reverse,
+            // Assigned as the move is sent.
+            seq: 0,
         })
     }
 
+    /// Obtain the turn radius, in metres, of the center of mass for a given
+    /// indexed turn `radius`.
+    ///
+    /// Returns infinity for straight moves.
+    pub fn turn_radius(&self, radius: i32) -> Result<f64, Error> {
+        if radius == 0 {
+            return Ok(f64::INFINITY);
+        }
+
+        self.model
+            .turn_radii
+            .get((radius.unsigned_abs() as usize).saturating_sub(1))
+            .map(|r| r.radius)
+            .ok_or(Error::RadiusNotSupported)
+    }
+
+    /// Obtain the heading change resulting from a move with a given indexed
+    /// turn `radius` and `distance`, as for `generate_move`.
+    ///
+    /// The heading change is in radians, counter-clockwise positive.
+    pub fn heading_change(&self, radius: i32, distance: f64) -> Result<f64, Error> {
+        Ok(radius.signum() as f64 * distance / self.turn_radius(radius)?)
+    }
+
+    /// Obtain the distance to move with a given indexed turn `radius` to
+    /// change heading by `heading_change` radians, counter-clockwise
+    /// positive.
+    ///
+    /// Negative distances are returned when reversing is required.
+    pub fn distance_for_heading_change(
+        &self,
+        radius: i32,
+        heading_change: f64,
+    ) -> Result<f64, Error> {
+        if radius == 0 {
+            return Err(Error::RadiusNotSupported);
+        }
+
+        Ok(radius.signum() as f64 * heading_change * self.turn_radius(radius)?)
+    }
+
     pub fn set_motion_profile_limits(
         &mut self,
         max_jerk: f64,
@@ -127,4 +215,48 @@ impl Model {
 
         Ok(())
     }
+
+    pub fn set_steering_setup_time(&mut self, steering_setup_time: f64) -> Result<(), Error> {
+        if steering_setup_time.is_nan() || steering_setup_time < 0. {
+            return Err(Error::SteeringSetupTimeNegative);
+        }
+
+        self.motion.steering_setup_time = steering_setup_time;
+
+        Ok(())
+    }
+
+    /// Check that PID parameters are suitable for the device's position
+    /// control loops.
+    pub fn check_pid_params(params: &PidParams) -> Result<(), Error> {
+        let values = [
+            params.kp,
+            params.ki,
+            params.kd,
+            params.p_limit,
+            params.i_limit,
+            params.d_limit,
+            params.output_limit,
+        ];
+        if values.iter().all(|v| v.is_finite() && *v >= 0.) {
+            Ok(())
+        } else {
+            Err(Error::PidParamsInvalid)
+        }
+    }
+
+    /// Set the PID parameters of the left & right wheels' position control
+    /// loops.
+    ///
+    /// The parameters are only recorded, and must be uploaded to the device
+    /// separately.
+    pub fn set_pid_params(&mut self, left: PidParams, right: PidParams) -> Result<(), Error> {
+        Self::check_pid_params(&left)?;
+        Self::check_pid_params(&right)?;
+
+        self.motion.pid_left = left;
+        self.motion.pid_right = right;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,441 @@
+/// Move tracking.
+///
+/// Tracks moves issued through the server, so that the outcome of a move can
+/// be waited for.
+use hdcomm_core::rpc::MoveStatusRepBody;
+use hdcomm_core::stream::{MoveEvent, MoveEventKind};
+use hdcomm_host::error::RPCError;
+use hdcomm_host::proxy::Proxy;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Outcome of a move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The move ran to completion.
+    Completed,
+    /// The move was cancelled.
+    Cancelled,
+    /// The move was stopped by the device before completion, without being
+    /// cancelled.
+    Aborted,
+    /// Another move was started before the move was observed to complete.
+    Preempted,
+    /// The move did not complete in time.
+    TimedOut,
+}
+
+/// Move as sent to the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Sent {
+    /// Sequence number sent to the device with the move, echoed in its
+    /// lifecycle events.
+    device_seq: u16,
+    /// Whether the move is done in reverse.
+    reverse: bool,
+}
+
+/// State of the latest move issued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Latest {
+    /// Sequence number of the move.
+    seq: u64,
+    /// The move as sent to the device.
+    sent: Sent,
+    /// Whether a cancellation of the move was requested.
+    cancelled: bool,
+    /// Outcome of the move, if reported by the device.
+    finished: Option<Outcome>,
+    /// Move queued to start once the move completes, if any.
+    queued: Option<Sent>,
+    /// Outcome of the previous move, if the move was started from the
+    /// queue as the previous move finished.
+    previous: Option<Outcome>,
+}
+
+impl Latest {
+    /// Outcome of the move with sequence number `seq`, if it finished.
+    fn outcome(&self, seq: u64) -> Option<Outcome> {
+        if self.seq == seq {
+            self.finished
+        } else if self.seq == seq + 1 && self.previous.is_some() {
+            self.previous
+        } else if self.seq + 1 == seq && self.queued.is_some() {
+            None
+        } else {
+            Some(Outcome::Preempted)
+        }
+    }
+
+    /// State once the latest move finished with `outcome`.
+    ///
+    /// The queued move, if any, becomes the latest move: it is started if
+    /// the latest move completed, and discarded with the same outcome
+    /// otherwise.
+    fn finish(self, outcome: Outcome) -> Self {
+        let sent = match self.queued {
+            Some(sent) => sent,
+            None => {
+                return Self {
+                    finished: Some(outcome),
+                    ..self
+                }
+            }
+        };
+
+        Self {
+            seq: self.seq + 1,
+            sent,
+            cancelled: self.cancelled,
+            finished: match outcome {
+                Outcome::Completed => None,
+                outcome => Some(outcome),
+            },
+            queued: None,
+            previous: Some(outcome),
+        }
+    }
+}
+
+/// Tracks moves issued to the device.
+///
+/// Each move accepted or queued by the device is assigned a sequence
+/// number. Moves are also sent with a device sequence number, so that
+/// lifecycle events relating to earlier moves can be told apart.
+pub struct MoveTracker {
+    /// State of the latest move, locked while it is updated so that
+    /// concurrent updates are never lost.
+    latest: Mutex<Latest>,
+    sender: watch::Sender<Latest>,
+    /// Keeps the channel open, as updates are discarded by the sender while
+    /// there are no receivers.
+    receiver: watch::Receiver<Latest>,
+    /// Device sequence number of the next move sent.
+    next_device_seq: AtomicU16,
+}
+
+impl MoveTracker {
+    /// Create a new move tracker.
+    pub fn new() -> Self {
+        let latest = Latest {
+            seq: 0,
+            sent: Sent {
+                device_seq: 0,
+                reverse: false,
+            },
+            cancelled: false,
+            finished: None,
+            queued: None,
+            previous: None,
+        };
+        let (sender, receiver) = watch::channel(latest);
+        Self {
+            latest: Mutex::new(latest),
+            sender,
+            receiver,
+            next_device_seq: AtomicU16::new(1),
+        }
+    }
+
+    /// Obtain the device sequence number to send the next move with.
+    pub fn next_device_seq(&self) -> u16 {
+        self.next_device_seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Update the state of the latest move with `f`, which returns the new
+    /// state along with a value passed to the caller.
+    fn update<R>(&self, f: impl FnOnce(Latest) -> (Latest, R)) -> R {
+        let mut latest = self.latest.lock().unwrap();
+        let (next, r) = f(*latest);
+        if next != *latest {
+            *latest = next;
+            self.sender.send(next).ok();
+        }
+        r
+    }
+
+    /// Record that a move sent with `device_seq` was accepted by the device.
+    ///
+    /// `reverse` tells whether the move is done in reverse. Returns the
+    /// sequence number of the move.
+    pub fn started(&self, device_seq: u16, reverse: bool) -> u64 {
+        self.update(|latest| {
+            let seq = latest.seq + 1;
+            let next = Latest {
+                seq,
+                sent: Sent {
+                    device_seq,
+                    reverse,
+                },
+                cancelled: false,
+                finished: None,
+                queued: None,
+                previous: None,
+            };
+            (next, seq)
+        })
+    }
+
+    /// Record that a move sent with `device_seq` was queued by the device
+    /// behind the latest move.
+    ///
+    /// `reverse` tells whether the move is done in reverse. Returns the
+    /// sequence number of the queued move.
+    pub fn queued(&self, device_seq: u16, reverse: bool) -> u64 {
+        self.update(|latest| {
+            let queued = Latest {
+                queued: Some(Sent {
+                    device_seq,
+                    reverse,
+                }),
+                ..latest
+            };
+            // The latest move may have been reported finished before the
+            // device's reply to the queued move was received.
+            let next = match latest.finished {
+                Some(outcome) => Latest {
+                    finished: None,
+                    ..queued
+                }
+                .finish(outcome),
+                None => queued,
+            };
+            (next, latest.seq + 1)
+        })
+    }
+
+    /// Record that a cancellation of the latest move was requested.
+    ///
+    /// The device discards queued moves on cancellation.
+    ///
+    /// Should be called before the cancellation is sent to the device, so
+    /// that waiters observing the move stop never mistake it for a
+    /// completion.
+    pub fn cancelled(&self) {
+        self.update(|latest| {
+            let next = Latest {
+                cancelled: true,
+                ..latest
+            };
+            (next, ())
+        })
+    }
+
+    /// Wait for the state of the latest move to change.
+    pub async fn changed(&self) {
+        // The sender is held by the tracker, so the channel never closes.
+        let _ = self.receiver.clone().changed().await;
+    }
+
+    /// Whether the latest move is yet to be reported finished.
+    pub fn in_progress(&self) -> bool {
+        let latest = self.latest.lock().unwrap();
+        latest.seq > 0 && latest.finished.is_none()
+    }
+
+    /// Whether the latest move is a forward move yet to be reported
+    /// finished.
+    pub fn forward_in_progress(&self) -> bool {
+        let latest = self.latest.lock().unwrap();
+        latest.seq > 0 && !latest.sent.reverse && latest.finished.is_none()
+    }
+
+    /// Record a move lifecycle event received from the device.
+    ///
+    /// Events relating to moves other than the latest move, e.g. the abort
+    /// of a cancelled move received after the next move started, are
+    /// ignored.
+    pub fn handle_event(&self, event: &MoveEvent) {
+        self.update(|latest| {
+            if event.seq != latest.sent.device_seq {
+                return (latest, ());
+            }
+
+            let outcome = match event.kind {
+                MoveEventKind::Completed(_) => Outcome::Completed,
+                MoveEventKind::Aborted(_) if latest.cancelled => Outcome::Cancelled,
+                MoveEventKind::Aborted(_) => Outcome::Aborted,
+                MoveEventKind::Started | MoveEventKind::SteeringSettled => return (latest, ()),
+            };
+
+            match latest.finished {
+                None => (latest.finish(outcome), ()),
+                Some(_) => (latest, ()),
+            }
+        })
+    }
+
+    /// Wait for the move with sequence number `seq` to finish.
+    ///
+    /// The move is considered finished once the device reports its outcome
+    /// through a move lifecycle event, or the device's move status, polled
+    /// every `poll_interval`, shows that no move is being executed.
+    ///
+    /// `started` is the time at which the move was accepted. Returns the
+    /// outcome of the move, and the time it took to finish, measured from
+    /// `started`.
+    pub async fn wait<P: Proxy>(
+        &self,
+        proxy: &P,
+        seq: u64,
+        started: Instant,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<(Outcome, Duration), RPCError> {
+        let mut latest = self.receiver.clone();
+        let deadline = tokio::time::sleep_until(started + timeout);
+        tokio::pin!(deadline);
+
+        let outcome = loop {
+            if let Some(outcome) = latest.borrow().outcome(seq) {
+                break outcome;
+            }
+
+            tokio::select! {
+                _ = &mut deadline => break Outcome::TimedOut,
+                _ = latest.changed() => continue,
+                _ = tokio::time::sleep(poll_interval) => {
+                    if proxy.move_status(()).await? == MoveStatusRepBody::NoCommand {
+                        // The move may have stopped due to a cancellation
+                        // or another move.
+                        let current = *latest.borrow();
+                        match current.outcome(seq) {
+                            Some(outcome) => break outcome,
+                            // Still queued behind a move yet to be reported
+                            // finished.
+                            None if current.seq != seq => {}
+                            None if current.cancelled => break Outcome::Cancelled,
+                            None => break Outcome::Completed,
+                        }
+                    }
+                }
+            }
+        };
+
+        Ok((outcome, started.elapsed()))
+    }
+}
+
+impl Default for MoveTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hdcomm_core::stream::MoveSummary;
+
+    fn summary() -> MoveSummary {
+        MoveSummary {
+            encoder_counts: [0; 2],
+            tracking_error: [0.; 2],
+        }
+    }
+
+    fn event(seq: u16, kind: MoveEventKind) -> MoveEvent {
+        MoveEvent {
+            time_ms: 0,
+            seq,
+            kind,
+        }
+    }
+
+    fn outcome(moves: &MoveTracker, seq: u64) -> Option<Outcome> {
+        moves.receiver.borrow().outcome(seq)
+    }
+
+    #[test]
+    fn queued_move_starts_once_latest_completes() {
+        let moves = MoveTracker::new();
+        let first = moves.started(1, false);
+        let second = moves.queued(2, false);
+        assert_eq!(second, first + 1);
+        assert_eq!(outcome(&moves, first), None);
+        assert_eq!(outcome(&moves, second), None);
+
+        moves.handle_event(&event(1, MoveEventKind::Completed(summary())));
+        assert_eq!(outcome(&moves, first), Some(Outcome::Completed));
+        assert_eq!(outcome(&moves, second), None);
+
+        moves.handle_event(&event(2, MoveEventKind::Completed(summary())));
+        assert_eq!(outcome(&moves, second), Some(Outcome::Completed));
+    }
+
+    #[test]
+    fn queued_move_discarded_once_latest_cancelled() {
+        let moves = MoveTracker::new();
+        let first = moves.started(1, false);
+        let second = moves.queued(2, false);
+
+        moves.cancelled();
+        moves.handle_event(&event(1, MoveEventKind::Aborted(summary())));
+        assert_eq!(outcome(&moves, first), Some(Outcome::Cancelled));
+        assert_eq!(outcome(&moves, second), Some(Outcome::Cancelled));
+    }
+
+    #[test]
+    fn move_queued_after_latest_finished() {
+        let moves = MoveTracker::new();
+        let first = moves.started(1, false);
+        moves.handle_event(&event(1, MoveEventKind::Completed(summary())));
+
+        // The reply to the queued move arrives after the completion of the
+        // move it continues.
+        let second = moves.queued(2, false);
+        assert_eq!(outcome(&moves, first), Some(Outcome::Completed));
+        assert_eq!(outcome(&moves, second), None);
+    }
+
+    #[test]
+    fn started_move_preempts_latest() {
+        let moves = MoveTracker::new();
+        let first = moves.started(1, false);
+        let second = moves.started(2, false);
+        assert_eq!(outcome(&moves, first), Some(Outcome::Preempted));
+        assert_eq!(outcome(&moves, second), None);
+    }
+
+    #[test]
+    fn events_of_previous_moves_ignored() {
+        let moves = MoveTracker::new();
+        moves.started(1, false);
+        moves.cancelled();
+        let second = moves.started(2, false);
+
+        moves.handle_event(&event(1, MoveEventKind::Aborted(summary())));
+        assert_eq!(outcome(&moves, second), None);
+
+        moves.handle_event(&event(2, MoveEventKind::Aborted(summary())));
+        assert_eq!(outcome(&moves, second), Some(Outcome::Aborted));
+    }
+
+    #[test]
+    fn move_in_progress() {
+        let moves = MoveTracker::new();
+        assert!(!moves.in_progress());
+        assert!(!moves.forward_in_progress());
+
+        moves.started(1, false);
+        moves.queued(2, true);
+        assert!(moves.in_progress());
+        assert!(moves.forward_in_progress());
+
+        // The queued move reverses once the first move completes.
+        moves.handle_event(&event(1, MoveEventKind::Completed(summary())));
+        assert!(moves.in_progress());
+        assert!(!moves.forward_in_progress());
+
+        moves.handle_event(&event(2, MoveEventKind::Completed(summary())));
+        assert!(!moves.in_progress());
+
+        moves.started(3, false);
+        moves.handle_event(&event(3, MoveEventKind::Aborted(summary())));
+        assert!(!moves.in_progress());
+        assert!(!moves.forward_in_progress());
+    }
+}
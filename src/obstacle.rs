@@ -0,0 +1,88 @@
+/// Obstacle estimation from front distance sensor readings.
+use hdcomm_core::stream::FrontDistanceBody;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Obstacle in front of the robot.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Obstacle {
+    /// Device timestamp of the last reading.
+    ///
+    /// `None` when there were no readings.
+    pub timestamp: Option<f64>,
+    /// Distance to the obstacle, in metres.
+    ///
+    /// Infinity when there were no readings.
+    pub distance: f64,
+}
+
+/// Obstacle estimator.
+///
+/// Takes the median of the latest readings, so that spurious readings of a
+/// noisy sensor are rejected. Failed readings are ignored.
+pub struct ObstacleFilter {
+    /// Number of readings the median is taken over.
+    window: usize,
+    /// Latest readings, in metres, oldest first.
+    readings: VecDeque<f64>,
+    /// Device timestamp of the last reading, in seconds.
+    timestamp: Option<f64>,
+    /// Time the last reading was received at.
+    received: Option<Instant>,
+}
+
+impl ObstacleFilter {
+    /// Create an obstacle estimator taking the median over `window`
+    /// readings.
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            readings: VecDeque::with_capacity(window),
+            timestamp: None,
+            received: None,
+        }
+    }
+
+    /// Update the estimate with a new reading.
+    pub fn update(&mut self, reading: &FrontDistanceBody) {
+        let distance = match reading.distance {
+            Some(distance) if !distance.is_nan() => distance as f64,
+            _ => return,
+        };
+
+        if self.readings.len() >= self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(distance);
+        self.timestamp = Some(reading.end_time_ms as f64 / 1e3);
+        self.received = Some(Instant::now());
+    }
+
+    /// Obtain the current obstacle estimate.
+    pub fn obstacle(&self) -> Obstacle {
+        let mut sorted: Vec<f64> = self.readings.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let distance = match sorted.len() {
+            0 => f64::INFINITY,
+            n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.,
+            n => sorted[n / 2],
+        };
+
+        Obstacle {
+            timestamp: self.timestamp,
+            distance,
+        }
+    }
+
+    /// Obtain the current obstacle estimate, unless no reading was received
+    /// within `max_age`.
+    pub fn recent_obstacle(&self, max_age: Duration) -> Option<Obstacle> {
+        match self.received {
+            Some(received) if received.elapsed() <= max_age => Some(self.obstacle()),
+            _ => None,
+        }
+    }
+}
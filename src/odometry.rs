@@ -0,0 +1,133 @@
+/// Dead-reckoning pose estimation.
+use crate::config::Model as ModelConfig;
+use hdcomm_core::stream::OdometryBody;
+
+/// Robot pose.
+///
+/// The pose is relative to the pose of the robot when odometry started,
+/// with the x axis pointing forwards and the y axis pointing to the left.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Pose {
+    /// Device timestamp of the last odometry update.
+    ///
+    /// `None` when there were no updates.
+    pub timestamp: Option<f64>,
+    /// Position of the center of mass, in metres.
+    pub x: f64,
+    /// Position of the center of mass, in metres.
+    pub y: f64,
+    /// Heading, in radians, counter-clockwise positive.
+    pub theta: f64,
+    /// Velocity of the midpoint of the rear wheels, in ms^-1, positive
+    /// when driving forwards.
+    pub velocity: f64,
+}
+
+/// Dead-reckoning pose estimator.
+///
+/// Integrates the distance travelled by the drive wheels along the robot's
+/// heading. The heading is obtained from the AHRS yaw if available, and
+/// from the difference in distance travelled by the drive wheels otherwise.
+pub struct Odometry {
+    /// Robot model configuration.
+    config: ModelConfig,
+    /// Encoder counts at the last update.
+    counts: Option<[i32; 2]>,
+    /// AHRS yaw corresponding to a heading of zero, in radians.
+    yaw_origin: Option<f64>,
+    /// Position of the midpoint of the rear wheels, in metres.
+    ///
+    /// The origin is the starting position of the center of mass, which lies
+    /// `a2` in front of the rear wheels.
+    x: f64,
+    /// Position of the midpoint of the rear wheels, in metres.
+    y: f64,
+    /// Heading, in radians.
+    theta: f64,
+    /// Velocity of the midpoint of the rear wheels, in ms^-1.
+    velocity: f64,
+    /// Device timestamp of the last update, in seconds.
+    timestamp: Option<f64>,
+}
+
+impl Odometry {
+    /// Create a pose estimator for the given robot model.
+    pub fn new(config: &ModelConfig) -> Self {
+        Self {
+            config: config.clone(),
+            counts: None,
+            yaw_origin: None,
+            x: -config.a2,
+            y: 0.,
+            theta: 0.,
+            velocity: 0.,
+            timestamp: None,
+        }
+    }
+
+    /// Update the estimate with a new odometry sample.
+    ///
+    /// `yaw` is the latest AHRS yaw in radians, counter-clockwise positive,
+    /// if any.
+    pub fn update(&mut self, odometry: &OdometryBody, yaw: Option<f64>) {
+        let counts = odometry.encoder_counts;
+        let timestamp = odometry.time_ms as f64 / 1e3;
+        let last_timestamp = self.timestamp.replace(timestamp);
+
+        let last = match self.counts.replace(counts) {
+            Some(last) => last,
+            None => {
+                // Nothing to integrate against yet.
+                if let Some(yaw) = yaw {
+                    self.yaw_origin = Some(yaw - self.theta);
+                }
+                return;
+            }
+        };
+
+        let dl = counts[0].wrapping_sub(last[0]) as f64 / self.config.counts_per_metre;
+        let dr = counts[1].wrapping_sub(last[1]) as f64 / self.config.counts_per_metre;
+        let ds = (dl + dr) / 2.;
+        self.velocity = match last_timestamp {
+            Some(last) if timestamp > last => ds / (timestamp - last),
+            _ => 0.,
+        };
+
+        let theta = match yaw {
+            Some(yaw) => {
+                let origin = *self.yaw_origin.get_or_insert(yaw - self.theta);
+                self.theta + wrap_angle(yaw - origin - self.theta)
+            }
+            None => self.theta + (dr - dl) / self.config.w,
+        };
+
+        // Integrate along the arc using the midpoint heading.
+        let heading = (self.theta + theta) / 2.;
+        self.x += ds * heading.cos();
+        self.y += ds * heading.sin();
+        self.theta = theta;
+    }
+
+    /// Obtain the current pose estimate.
+    pub fn pose(&self) -> Pose {
+        Pose {
+            timestamp: self.timestamp,
+            x: self.x + self.config.a2 * self.theta.cos(),
+            y: self.y + self.config.a2 * self.theta.sin(),
+            theta: wrap_angle(self.theta),
+            velocity: self.velocity,
+        }
+    }
+}
+
+/// Wrap an angle in radians to `(-pi, pi]`.
+fn wrap_angle(angle: f64) -> f64 {
+    use std::f64::consts::PI;
+
+    let wrapped = angle.rem_euclid(2. * PI);
+    if wrapped > PI {
+        wrapped - 2. * PI
+    } else {
+        wrapped
+    }
+}
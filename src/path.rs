@@ -0,0 +1,117 @@
+/// Path execution control.
+///
+/// Paths are sequences of moves executed one after another by the server.
+/// At most one path is executed at any one time.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Command issued to the executing path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Execute the remaining segments.
+    Run,
+    /// Hold before starting the next segment.
+    Pause,
+    /// Stop executing the path, discarding the remaining segments.
+    Cancel,
+}
+
+/// Controls the executing path.
+pub struct PathControl {
+    /// Whether a path is being executed.
+    active: AtomicBool,
+    sender: watch::Sender<Command>,
+    /// Keeps the channel open, as updates are discarded by the sender while
+    /// there are no receivers.
+    receiver: watch::Receiver<Command>,
+}
+
+impl PathControl {
+    /// Create a new path controller.
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(Command::Run);
+        Self {
+            active: AtomicBool::new(false),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Begin executing a path.
+    ///
+    /// Returns `None` if another path is being executed.
+    pub fn begin(self: &Arc<Self>) -> Option<ActivePath> {
+        if self.active.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        self.sender.send(Command::Run).ok();
+
+        Some(ActivePath {
+            control: self.clone(),
+            commands: self.receiver.clone(),
+        })
+    }
+
+    /// Issue a command to the executing path.
+    ///
+    /// Returns `false` if no path is being executed. A cancelled path cannot
+    /// be resumed or paused.
+    pub fn command(&self, command: Command) -> bool {
+        if !self.active.load(Ordering::Acquire) {
+            return false;
+        }
+
+        if *self.receiver.borrow() != Command::Cancel {
+            self.sender.send(command).ok();
+        }
+
+        true
+    }
+}
+
+impl Default for PathControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Path being executed.
+///
+/// Dropping the path allows another path to be executed.
+pub struct ActivePath {
+    control: Arc<PathControl>,
+    commands: watch::Receiver<Command>,
+}
+
+impl ActivePath {
+    /// Obtain the latest command issued to the path.
+    pub fn command(&self) -> Command {
+        *self.commands.borrow()
+    }
+
+    /// Wait until the path is no longer paused.
+    ///
+    /// Returns `false` if the path was cancelled.
+    pub async fn resumed(&mut self) -> bool {
+        loop {
+            match *self.commands.borrow() {
+                Command::Run => return true,
+                Command::Cancel => return false,
+                Command::Pause => {}
+            }
+
+            if self.commands.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for ActivePath {
+    fn drop(&mut self) {
+        self.control.sender.send(Command::Run).ok();
+        self.control.active.store(false, Ordering::Release);
+    }
+}
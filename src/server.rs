@@ -1,20 +1,46 @@
-use crate::config::Config;
+use crate::ahrs::Estimate;
+use crate::battery::{Level as BatteryLevel, Monitor as BatteryMonitor, Reading as BatteryReading};
+use crate::calibration::{Bias, Error as CalibrationError};
+use crate::config::{self, Collision as CollisionConfig, Config, Motion as MotionConfig};
 use crate::model::{Error as ModelError, Model};
+use crate::moves::{MoveTracker, Outcome};
+use crate::path::{ActivePath, Command as PathCommand, PathControl};
 use crate::stream::Processor;
-use hdcomm_core::rpc::{self, MoveStatusRepBody, PidParamUpdateReqBody};
+use crate::teleop::{self, TeleOpControl, TeleOpSession};
+use hdcomm_core::rpc::{
+    self, FrontDistanceStreamReqBody, LeaseReqBody, MoveReqBody, MoveStatusRepBody,
+    PidParamUpdateRepBody, PidParamUpdateReqBody, RawTeleOpRepBody, RawTeleOpReqBody, Version,
+};
+use hdcomm_core::stream::{FailsafeEvent, MoveEvent};
+use hdcomm_host::error::RPCError;
 use hdcomm_host::proxy::{Proxy, ProxyImpl};
+use hdcomm_host::recording::Recorder;
+use hdcomm_host::subscription::Subscription;
+use hdcomm_host::supervisor::{Backoff, Connection, ConnectionState};
 use hdcomm_server::hd_comm_server::HdComm;
 use hdcomm_server::{
-    move_status_response, FrontDistanceResponse, HeadingResponse, MoveRequest, MoveResponse,
-    MoveStatusResponse, PingResponse, RadiiResponse, VinReadingResponse,
+    battery_update, move_and_wait_response, move_status_response, orientation_update,
+    path_progress, tele_op_status, AhrsStatsResponse, AngularRateResponse, BatteryUpdate,
+    CalibrateImuRequest, CalibrateImuResponse, ExecutePathRequest, FrontDistanceResponse,
+    HeadingCorrectedMoveRequest, HeadingCorrectedMoveResponse, HeadingResponse, MotionParams,
+    MoveAndWaitRequest, MoveAndWaitResponse, MoveRequest, MoveResponse, MoveStatusResponse,
+    ObstacleResponse, OrientationUpdate, PathProgress, PidParams, PingResponse, PoseResponse,
+    Quaternion, RadiiResponse, SetHeadingReferenceRequest, TeleOpCommand, TeleOpStatus,
+    UnwrappedHeadingResponse, Vector3, VinReadingResponse, WatchBatteryRequest,
+    WatchOrientationRequest,
 };
-use prost_types::Duration as GrpcDuration;
-use std::sync::Arc;
+use nalgebra::Vector3 as NVector3;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch, Mutex};
 use tokio::task::JoinHandle;
-use tokio_serial::Error as SerialError;
-use tonic::{Request, Response, Status};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
 
 pub mod hdcomm_server {
     tonic::include_proto!("hdcomm");
@@ -22,41 +48,893 @@ pub mod hdcomm_server {
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("serial initialization")]
-    Serial(#[from] SerialError),
+    #[error("connection supervisor terminated")]
+    SupervisorTerminated,
     #[error("initial parameter upload")]
     InitialParamUpload,
+    #[error("session recording: {0}")]
+    Recording(std::io::Error),
 }
 
 /// HdComm gRPC server implementation.
 pub struct ServerImpl {
-    /// Robot model.
-    model: Model,
+    /// Robot model, tuned at runtime.
+    model: Arc<RwLock<Model>>,
+    /// Held while the motion parameters are updated, so that concurrent
+    /// updates are uploaded to the device and applied to the model in the
+    /// same order.
+    motion_update: Mutex<()>,
     /// Server configuration.
     config: Config,
-    /// Router join handle.
-    router_handle: JoinHandle<()>,
+    /// Connection supervisor join handle.
+    supervisor_handle: JoinHandle<()>,
     /// Host -> device RPC proxy.
     proxy: ProxyImpl,
     /// Stream processor.
     sp: Arc<Processor>,
     /// Stream processor join handle.
     sp_handle: JoinHandle<()>,
+    /// Reconnection handler join handle.
+    reconnect_handle: JoinHandle<()>,
+    /// Tracker of moves issued to the device.
+    moves: Arc<MoveTracker>,
+    /// Move event handler join handle.
+    move_events_handle: JoinHandle<()>,
+    /// Path execution controller.
+    path: Arc<PathControl>,
+    /// Teleoperation controller.
+    teleop: Arc<TeleOpControl>,
+    /// Move lease holder join handle.
+    move_lease_handle: JoinHandle<()>,
+    /// Battery monitor.
+    battery: Arc<BatteryMonitor>,
+    /// Battery monitor join handle.
+    battery_handle: JoinHandle<()>,
+    /// Collision guard join handle.
+    collision_handle: JoinHandle<()>,
+}
+
+/// Uploads the PID parameters in the motion configuration to the device.
+async fn upload_pid_params(
+    proxy: &ProxyImpl,
+    motion: &MotionConfig,
+) -> Result<PidParamUpdateRepBody, RPCError> {
+    let reply = proxy
+        .pid_param_update(PidParamUpdateReqBody {
+            params: [motion.pid_left.clone(), motion.pid_right.clone()],
+            update_interval_ms: (motion.pid_update_interval * 1e3) as u16,
+        })
+        .await?;
+
+    match reply {
+        PidParamUpdateRepBody::Updated => log::info!("sent PID parameters"),
+        PidParamUpdateRepBody::Busy => log::warn!("PID parameters not updated: device busy"),
+    }
+
+    Ok(reply)
+}
+
+/// Re-uploads the current PID parameters, and re-enables the front distance
+/// stream, every time the device reconnects.
+///
+/// `streaming` is updated with whether the device streams front distance
+/// readings.
+async fn handle_reconnects(
+    proxy: ProxyImpl,
+    model: Arc<RwLock<Model>>,
+    sp: Arc<Processor>,
+    front_distance_interval: f64,
+    streaming: Arc<AtomicBool>,
+    mut state: watch::Receiver<Connection>,
+) {
+    let mut generation = state.borrow().generation;
+    let mut connected = state.borrow().state == ConnectionState::Connected;
+
+    while state.changed().await.is_ok() {
+        let current = *state.borrow();
+        let now_connected = current.state == ConnectionState::Connected;
+
+        if current.state == ConnectionState::Incompatible {
+            log::error!("device speaks an incompatible protocol version");
+        }
+
+        // Intermediate states may be missed if the connection is lost and
+        // reestablished quickly, but never a change of generation.
+        if current.generation != generation {
+            generation = current.generation;
+            log::info!("device reconnected: {:?}", proxy.device_info());
+            sp.resync();
+            let motion = model.read().unwrap().motion.clone();
+            if let Err(e) = upload_pid_params(&proxy, &motion).await {
+                log::warn!("PID parameter upload: {}", e);
+            }
+            if front_distance_interval > 0. {
+                let enabled = enable_front_distance_stream(&proxy, front_distance_interval).await;
+                streaming.store(enabled, Ordering::Relaxed);
+            }
+        } else if connected && !now_connected {
+            log::warn!("device disconnected");
+        }
+
+        connected = now_connected;
+    }
+}
+
+/// Time allowed for a move to finish beyond its estimated time required,
+/// unless specified by the client.
+const MOVE_TIMEOUT_MARGIN: Duration = Duration::from_secs(2);
+
+/// Interval between move status polls while waiting for a move.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Interval between move status polls while waiting for a move, if the
+/// device reports the outcome of moves through move lifecycle events.
+///
+/// Polling still takes place in case the event is lost.
+const STATUS_POLL_INTERVAL_SLOW: Duration = Duration::from_millis(500);
+
+/// First protocol version with move lifecycle events.
+const MOVE_EVENTS_VERSION: Version = Version { major: 1, minor: 2 };
+
+/// First protocol version in which the device queues moves continuing the
+/// move being executed.
+const BLENDED_MOVES_VERSION: Version = Version { major: 1, minor: 4 };
+
+/// Time allowed for the orientation estimate to settle after a move, before
+/// the heading change of the move is measured.
+const HEADING_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Age, in front distance sampling intervals, beyond which the obstacle
+/// estimate is considered stale by the collision guard.
+const OBSTACLE_MAX_AGE_INTERVALS: f64 = 3.;
+
+/// Number of path progress reports buffered for the gRPC client.
+const PATH_PROGRESS_BUFFER_SIZE: usize = 16;
+
+/// Number of teleop status reports buffered for the gRPC client.
+const TELEOP_STATUS_BUFFER_SIZE: usize = 16;
+
+/// Time allowed for teleop commands to reach the device beyond the deadman
+/// interval, before the device stops the wheels by itself.
+const TELEOP_LEASE_MARGIN: Duration = Duration::from_millis(500);
+
+/// Actuator lease held by the device while moves are in progress.
+const MOVE_LEASE: Duration = Duration::from_secs(1);
+
+/// Interval between renewals of the actuator lease held while moves are in
+/// progress.
+const MOVE_LEASE_RENEWAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Number of battery updates buffered for each gRPC subscriber.
+const BATTERY_BUFFER_SIZE: usize = 16;
+
+/// Number of orientation updates buffered for each gRPC subscriber.
+const ORIENTATION_BUFFER_SIZE: usize = 64;
+
+/// Bias calibration window used when neither the client nor the
+/// configuration specify one.
+///
+/// In units of seconds.
+const DEFAULT_BIAS_CALIBRATION_WINDOW: f64 = 2.0;
+
+/// Time allowed for a bias calibration to finish beyond its window.
+const CALIBRATION_TIMEOUT_MARGIN: Duration = Duration::from_secs(2);
+
+impl From<Outcome> for move_and_wait_response::Outcome {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Completed => Self::Completed,
+            Outcome::Cancelled => Self::Cancelled,
+            Outcome::Preempted => Self::Preempted,
+            Outcome::TimedOut => Self::TimedOut,
+            Outcome::Aborted => Self::Aborted,
+        }
+    }
+}
+
+impl From<BatteryLevel> for battery_update::Level {
+    fn from(level: BatteryLevel) -> Self {
+        match level {
+            BatteryLevel::Normal => Self::Normal,
+            BatteryLevel::Warning => Self::Warning,
+            BatteryLevel::Low => Self::Low,
+            BatteryLevel::Critical => Self::Critical,
+        }
+    }
+}
+
+impl From<&BatteryReading> for BatteryUpdate {
+    fn from(reading: &BatteryReading) -> Self {
+        Self {
+            device_time: reading.timestamp,
+            voltage: reading.voltage,
+            smoothed_voltage: reading.smoothed,
+            level: battery_update::Level::from(reading.level) as i32,
+        }
+    }
+}
+
+impl From<&NVector3<f64>> for Vector3 {
+    fn from(v: &NVector3<f64>) -> Self {
+        Self {
+            x: v.x,
+            y: v.y,
+            z: v.z,
+        }
+    }
+}
+
+impl From<&rpc::PidParams> for PidParams {
+    fn from(params: &rpc::PidParams) -> Self {
+        Self {
+            kp: params.kp as f64,
+            ki: params.ki as f64,
+            kd: params.kd as f64,
+            p_limit: params.p_limit as f64,
+            i_limit: params.i_limit as f64,
+            d_limit: params.d_limit as f64,
+            output_limit: params.output_limit as f64,
+        }
+    }
+}
+
+impl From<&PidParams> for rpc::PidParams {
+    fn from(params: &PidParams) -> Self {
+        Self {
+            kp: params.kp as f32,
+            ki: params.ki as f32,
+            kd: params.kd as f32,
+            p_limit: params.p_limit as f32,
+            i_limit: params.i_limit as f32,
+            d_limit: params.d_limit as f32,
+            output_limit: params.output_limit as f32,
+        }
+    }
+}
+
+impl TryFrom<&TeleOpCommand> for RawTeleOpReqBody {
+    type Error = Status;
+
+    fn try_from(command: &TeleOpCommand) -> Result<Self, Self::Error> {
+        let convert = |value: Option<f64>, name: &str| match value {
+            Some(v) if !(-1. ..=1.).contains(&v) => Err(Status::invalid_argument(format!(
+                "{} must be within [-1, 1]",
+                name
+            ))),
+            v => Ok(v.map(|v| v as f32)),
+        };
+
+        Ok(Self {
+            steering: convert(command.steering, "steering")?,
+            wheel_l: convert(command.wheel_l, "wheel_l")?,
+            wheel_r: convert(command.wheel_r, "wheel_r")?,
+        })
+    }
+}
+
+impl From<&MotionConfig> for MotionParams {
+    fn from(motion: &MotionConfig) -> Self {
+        Self {
+            max_jerk: motion.max_jerk,
+            max_accel: motion.max_accel,
+            max_velocity: motion.max_velocity,
+            steering_setup_time: motion.steering_setup_time,
+            pid_left: Some((&motion.pid_left).into()),
+            pid_right: Some((&motion.pid_right).into()),
+        }
+    }
+}
+
+impl OrientationUpdate {
+    /// Create an orientation update from an orientation estimate.
+    fn new(estimate: &Estimate, include_sample: bool) -> Self {
+        let q = estimate.quaternion.quaternion();
+        Self {
+            device_time: estimate.sample.timestamp,
+            roll: estimate.angles.roll,
+            pitch: estimate.angles.pitch,
+            yaw: estimate.angles.yaw,
+            quaternion: Some(Quaternion {
+                w: q.w,
+                x: q.i,
+                y: q.j,
+                z: q.k,
+            }),
+            sample: if include_sample {
+                Some(orientation_update::Sample {
+                    acc: Some((&estimate.sample.acc).into()),
+                    gyro: Some((&estimate.sample.gyro).into()),
+                    mag: Some((&estimate.sample.mag).into()),
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Writes AHRS biases into the `[ahrs]` section of the configuration file
+/// at `path`.
+async fn persist_bias(path: &str, bias: &Bias) -> std::io::Result<()> {
+    let format = |v: &NVector3<f64>| format!("[{:.6e}, {:.6e}, {:.6e}]", v.x, v.y, v.z);
+
+    let document = tokio::fs::read_to_string(path).await?;
+    let document = config::set_value(&document, "ahrs", "gyro_bias", &format(&bias.gyro))
+        .and_then(|d| config::set_value(&d, "ahrs", "acc_bias", &format(&bias.acc)))
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "[ahrs] section not found")
+        })?;
+    tokio::fs::write(path, document).await
+}
+
+/// Estimates the AHRS biases over `window` seconds, and persists them to the
+/// configuration file.
+async fn calibrate_bias(sp: &Processor, window: f64) -> Result<Bias, Status> {
+    let timeout = Duration::from_secs_f64(window) + CALIBRATION_TIMEOUT_MARGIN;
+    let bias = tokio::time::timeout(timeout, sp.calibrate(window))
+        .await
+        .map_err(|_| Status::unavailable("no AHRS samples received"))?
+        .map_err(|e| match e {
+            CalibrationError::InProgress => Status::aborted(e.to_string()),
+            _ => Status::failed_precondition(e.to_string()),
+        })?;
+
+    log::info!("estimated AHRS biases: {:?}", bias);
+    if let Err(e) = persist_bias(config::PATH, &bias).await {
+        log::warn!("persisting AHRS biases: {}", e);
+    }
+
+    Ok(bias)
+}
+
+/// Converts an error generating a move into a gRPC status.
+fn invalid_move(e: ModelError) -> Status {
+    match e {
+        ModelError::RadiusNotSupported => Status::invalid_argument("radius not supported"),
+        ModelError::ProfileLimitsNonPositive => unreachable!(),
+        e => Status::invalid_argument(e.to_string()),
+    }
+}
+
+/// Commands the robot to perform a generated move.
+///
+/// Moves starting at a non-zero velocity are queued behind the move being
+/// executed.
+///
+/// Returns the sequence number of the move, and the time it was accepted at.
+async fn send_move(
+    proxy: &ProxyImpl,
+    moves: &MoveTracker,
+    mrb: MoveReqBody,
+) -> Result<(u64, Instant), Status> {
+    let device_seq = moves.next_device_seq();
+    let mrb = MoveReqBody {
+        seq: device_seq,
+        ..mrb
+    };
+    let reverse = mrb.reverse;
+    match proxy.move_cmd(mrb).await {
+        Ok(rpc::MoveRepBody::Accepted) => Ok((moves.started(device_seq, reverse), Instant::now())),
+        Ok(rpc::MoveRepBody::Queued) => Ok((moves.queued(device_seq, reverse), Instant::now())),
+        Ok(rpc::MoveRepBody::Busy) => Err(Status::unavailable("move in progress")),
+        Ok(rpc::MoveRepBody::Rejected) => Err(Status::aborted(
+            "move does not continue the move being executed",
+        )),
+        Err(e) => {
+            log::warn!("hdcomm RPC error: {}", e);
+            Err(Status::internal(e.to_string()))
+        }
+    }
+}
+
+/// Waits for the move with sequence number `seq`, accepted at `started`, to
+/// finish.
+///
+/// Returns the outcome of the move and the time it took to finish.
+async fn wait_move(
+    proxy: &ProxyImpl,
+    moves: &MoveTracker,
+    seq: u64,
+    started: Instant,
+    timeout: Duration,
+) -> Result<(Outcome, Duration), Status> {
+    // Move status only needs to be polled often if the device does not
+    // report the outcome of moves.
+    let poll_interval = match proxy.device_info() {
+        Some(device) if device.version >= MOVE_EVENTS_VERSION => STATUS_POLL_INTERVAL_SLOW,
+        _ => STATUS_POLL_INTERVAL,
+    };
+
+    match moves
+        .wait(proxy, seq, started, timeout, poll_interval)
+        .await
+    {
+        Ok((outcome, duration)) => {
+            log::info!("move {} finished: {:?} after {:?}", seq, outcome, duration);
+            Ok((outcome, duration))
+        }
+        Err(e) => {
+            log::warn!("hdcomm RPC error: {}", e);
+            Err(Status::internal(e.to_string()))
+        }
+    }
+}
+
+/// Executes the segments of a path one after another, reporting progress
+/// to `progress`.
+///
+/// Segments starting at a non-zero velocity are queued on the device as
+/// soon as the segment they continue starts. Pausing takes effect at the
+/// first segment starting from a standstill.
+///
+/// Execution continues even if the receiver of progress reports is dropped.
+async fn execute_path(
+    proxy: ProxyImpl,
+    moves: Arc<MoveTracker>,
+    mut path: ActivePath,
+    segments: Vec<MoveReqBody>,
+    progress: mpsc::Sender<Result<PathProgress, Status>>,
+) {
+    let started = Instant::now();
+    let report = |event: path_progress::Event, segment: usize, outcome: Option<Outcome>| {
+        let progress = progress.clone();
+        let report = PathProgress {
+            event: event as i32,
+            segment: segment as u32,
+            outcome: outcome
+                .map(|o| move_and_wait_response::Outcome::from(o) as i32)
+                .unwrap_or_default(),
+            elapsed: Some(started.elapsed().into()),
+        };
+        async move {
+            // Sending only fails if the client went away.
+            let _ = progress.send(Ok(report)).await;
+        }
+    };
+
+    let count = segments.len();
+    let mut outcome = Outcome::Completed;
+    let mut segments = segments.into_iter().enumerate().peekable();
+    // Sequence number, queueing time and timeout of the queued segment.
+    let mut queued: Option<(u64, Instant, Duration)> = None;
+
+    while let Some((index, mrb)) = segments.next() {
+        if queued.is_none() && path.command() == PathCommand::Pause {
+            log::info!("path paused before segment {}", index);
+            report(path_progress::Event::Paused, index, None).await;
+            if !path.resumed().await {
+                outcome = Outcome::Cancelled;
+                break;
+            }
+            log::info!("path resumed");
+            report(path_progress::Event::Resumed, index, None).await;
+        }
+        if path.command() == PathCommand::Cancel {
+            outcome = Outcome::Cancelled;
+            break;
+        }
+
+        let time_required = Duration::from_secs_f32(mrb.time_required());
+        let sent = match queued.take() {
+            Some(queued) => Ok(queued),
+            None => send_move(&proxy, &moves, mrb)
+                .await
+                .map(|(seq, started)| (seq, started, time_required + MOVE_TIMEOUT_MARGIN)),
+        };
+        let result = match sent {
+            Ok((seq, move_started, timeout)) => {
+                report(path_progress::Event::SegmentStarted, index, None).await;
+
+                // Queue the next segment if it continues this one.
+                let next = segments
+                    .peek()
+                    .filter(|(_, next)| next.params.conditions.v0 != 0.)
+                    .map(|(_, next)| next.clone());
+                let queue = match next {
+                    Some(next) => {
+                        let next_time_required = Duration::from_secs_f32(next.time_required());
+                        send_move(&proxy, &moves, next).await.map(|(seq, started)| {
+                            queued = Some((
+                                seq,
+                                started,
+                                time_required + next_time_required + MOVE_TIMEOUT_MARGIN,
+                            ));
+                        })
+                    }
+                    None => Ok(()),
+                };
+
+                match queue {
+                    Ok(()) => wait_move(&proxy, &moves, seq, move_started, timeout).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok((segment_outcome, _)) => {
+                report(
+                    path_progress::Event::SegmentFinished,
+                    index,
+                    Some(segment_outcome),
+                )
+                .await;
+                if segment_outcome != Outcome::Completed {
+                    outcome = segment_outcome;
+                    break;
+                }
+            }
+            Err(status) => {
+                log::warn!("path segment {}: {}", index, status.message());
+                let _ = progress.send(Err(status)).await;
+                return;
+            }
+        }
+    }
+
+    log::info!("path of {} segments finished: {:?}", count, outcome);
+    report(path_progress::Event::PathFinished, count, Some(outcome)).await;
+}
+
+/// Stops the wheels of a teleoperated robot.
+///
+/// Returns `false` if the wheels could not be stopped.
+async fn stop_teleop(proxy: &ProxyImpl) -> bool {
+    match proxy.raw_teleop(teleop::stop_command()).await {
+        Ok(RawTeleOpRepBody::Applied) => true,
+        // The wheels are under the control of a move.
+        Ok(RawTeleOpRepBody::Busy) => true,
+        Err(e) => {
+            log::warn!("hdcomm RPC error: {}", e);
+            false
+        }
+    }
+}
+
+/// Grants the device an actuator lease of `duration`, so that it stops the
+/// wheels by itself if the server goes away.
+///
+/// A zero `duration` releases the lease. Devices without support for leases
+/// are left unsupervised.
+async fn lease_actuators(proxy: &ProxyImpl, duration: Duration) {
+    let body = LeaseReqBody {
+        duration_ms: duration.as_millis() as u32,
+    };
+    match proxy.lease(body).await {
+        Ok(()) => {}
+        Err(RPCError::Unsupported) => {}
+        Err(e) => log::warn!("hdcomm RPC error: {}", e),
+    }
+}
+
+/// Renews the actuator lease while moves are in progress, so that the device
+/// stops the wheels by itself if the server goes away in the middle of a move
+/// or path, and releases it once they finish.
+///
+/// Teleop sessions hold their own lease.
+async fn hold_move_lease(proxy: ProxyImpl, moves: Arc<MoveTracker>, teleop: Arc<TeleOpControl>) {
+    let mut ticks = tokio::time::interval(MOVE_LEASE_RENEWAL_INTERVAL);
+    let mut held = false;
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = moves.changed() => {}
+        }
+
+        if teleop.active() {
+            held = false;
+            continue;
+        }
+
+        let in_progress = moves.in_progress();
+        if in_progress {
+            lease_actuators(&proxy, MOVE_LEASE).await;
+        } else if held {
+            lease_actuators(&proxy, Duration::ZERO).await;
+        }
+        held = in_progress;
+    }
+}
+
+/// Forwards teleop commands streamed by the client to the device, reporting
+/// the result of each to `status`.
+///
+/// The wheels are stopped if no command arrives within `deadman`, and once
+/// the command stream ends. The device holds an actuator lease for the
+/// duration of the session.
+async fn teleop(
+    proxy: ProxyImpl,
+    mut session: TeleOpSession,
+    mut commands: Streaming<TeleOpCommand>,
+    deadman: Duration,
+    status: mpsc::Sender<Result<TeleOpStatus, Status>>,
+) {
+    let report = |event: tele_op_status::Event| {
+        let status = status.clone();
+        async move {
+            // Sending only fails if the client went away.
+            let _ = status
+                .send(Ok(TeleOpStatus {
+                    event: event as i32,
+                }))
+                .await;
+        }
+    };
+
+    let mut failsafes = proxy.subscribe_to::<FailsafeEvent>();
+    let lease = deadman + TELEOP_LEASE_MARGIN;
+    lease_actuators(&proxy, lease).await;
+
+    loop {
+        let received = tokio::select! {
+            received = tokio::time::timeout(deadman, commands.message()) => received,
+            Ok(event) = failsafes.recv() => {
+                log::warn!("device stopped the wheels: {:?}", event);
+                session.applied(&teleop::stop_command());
+                report(tele_op_status::Event::Failsafe).await;
+                lease_actuators(&proxy, lease).await;
+                continue;
+            }
+        };
+
+        let command = match received {
+            Ok(Ok(Some(command))) => command,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                log::warn!("teleop command stream: {}", e);
+                break;
+            }
+            Err(_) => {
+                if session.driving() {
+                    log::warn!("no teleop command within {:?}, stopping", deadman);
+                    if stop_teleop(&proxy).await {
+                        session.applied(&teleop::stop_command());
+                        report(tele_op_status::Event::Stopped).await;
+                    }
+                }
+                lease_actuators(&proxy, lease).await;
+                continue;
+            }
+        };
+
+        let body = match RawTeleOpReqBody::try_from(&command) {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = status.send(Err(e)).await;
+                break;
+            }
+        };
+
+        match proxy.raw_teleop(body.clone()).await {
+            Ok(RawTeleOpRepBody::Applied) => {
+                session.applied(&body);
+                report(tele_op_status::Event::Applied).await;
+            }
+            Ok(RawTeleOpRepBody::Busy) => report(tele_op_status::Event::Busy).await,
+            Err(e) => {
+                log::warn!("hdcomm RPC error: {}", e);
+                let _ = status.send(Err(Status::internal(e.to_string()))).await;
+                break;
+            }
+        }
+    }
+
+    if session.driving() {
+        stop_teleop(&proxy).await;
+    }
+    lease_actuators(&proxy, Duration::ZERO).await;
+    log::info!("teleop session finished");
+}
+
+/// Samples the VIN reading every `interval` seconds, cancelling moves in
+/// progress while the battery level is critical.
+///
+/// Returns immediately if `interval` is not positive.
+async fn monitor_battery(
+    proxy: ProxyImpl,
+    battery: Arc<BatteryMonitor>,
+    moves: Arc<MoveTracker>,
+    path: Arc<PathControl>,
+    interval: f64,
+) {
+    if interval <= 0. {
+        return;
+    }
+
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(interval));
+    let mut level = BatteryLevel::Normal;
+
+    loop {
+        ticks.tick().await;
+
+        let reading = match proxy.get_vin_reading(()).await {
+            Ok(vin) => battery.update(&vin),
+            Err(e) => {
+                log::debug!("VIN reading: {}", e);
+                continue;
+            }
+        };
+
+        if reading.level != level {
+            if reading.level == BatteryLevel::Normal {
+                log::info!("battery level normal at {:.2}V", reading.smoothed);
+            } else {
+                log::warn!(
+                    "battery level {:?} at {:.2}V",
+                    reading.level,
+                    reading.smoothed
+                );
+            }
+            level = reading.level;
+        }
+
+        if level != BatteryLevel::Critical {
+            continue;
+        }
+        if let Ok(MoveStatusRepBody::Executing { .. }) = proxy.move_status(()).await {
+            log::warn!("battery level critical, cancelling move");
+            abort_move(&proxy, &moves, &path).await;
+        }
+    }
+}
+
+/// Cancels the move in progress, along with the path being executed, if
+/// any.
+async fn abort_move(proxy: &ProxyImpl, moves: &MoveTracker, path: &PathControl) {
+    path.command(PathCommand::Cancel);
+    moves.cancelled();
+    if let Err(e) = proxy.move_cancel(()).await {
+        log::warn!("hdcomm RPC error: {}", e);
+    }
+}
+
+/// Has the device stream front distance readings every `interval` seconds.
+///
+/// Devices without support for the stream are left as is. Returns whether
+/// the stream was enabled.
+async fn enable_front_distance_stream(proxy: &ProxyImpl, interval: f64) -> bool {
+    let body = FrontDistanceStreamReqBody {
+        interval_ms: (interval * 1e3) as u16,
+    };
+    match proxy.front_distance_stream(body).await {
+        Ok(()) => {
+            log::info!("enabled front distance stream");
+            true
+        }
+        Err(RPCError::Unsupported) => {
+            log::warn!("device does not stream front distance readings, collision guard disabled");
+            false
+        }
+        Err(e) => {
+            log::warn!("hdcomm RPC error: {}", e);
+            false
+        }
+    }
+}
+
+/// Cancels forward moves once the obstacle in front of the robot is closer
+/// than the distance required to stop, checking every sampling interval of
+/// the front distance stream.
+///
+/// Forward moves are also cancelled while no recent front distance reading
+/// is available. Nothing is checked while `streaming` tells that the device
+/// does not stream front distance readings, and the guard returns
+/// immediately if the stream is disabled in the configuration.
+async fn guard_collisions(
+    proxy: ProxyImpl,
+    sp: Arc<Processor>,
+    model: Arc<RwLock<Model>>,
+    moves: Arc<MoveTracker>,
+    path: Arc<PathControl>,
+    config: CollisionConfig,
+    streaming: Arc<AtomicBool>,
+) {
+    if config.sample_interval <= 0. {
+        return;
+    }
+
+    let interval = Duration::from_secs_f64(config.sample_interval);
+    let max_age = interval.mul_f64(OBSTACLE_MAX_AGE_INTERVALS);
+    let mut ticks = tokio::time::interval(interval);
+    // Whether the front distance readings are stale.
+    let mut stale = false;
+
+    loop {
+        ticks.tick().await;
+
+        // Reversing moves drive away from the obstacle.
+        if !streaming.load(Ordering::Relaxed) || !moves.forward_in_progress() {
+            continue;
+        }
+
+        let obstacle = sp.recent_obstacle(max_age);
+        match &obstacle {
+            Some(_) if stale => {
+                log::info!("front distance readings resumed");
+                stale = false;
+            }
+            None if !stale => {
+                log::warn!(
+                    "no front distance reading within {:?}, cancelling forward moves",
+                    max_age
+                );
+                stale = true;
+            }
+            _ => {}
+        }
+
+        let velocity = sp.pose().velocity;
+        let max_accel = model.read().unwrap().motion.max_accel;
+        let stop_distance = config.stop_distance(velocity, max_accel);
+        if let Some(obstacle) = &obstacle {
+            if obstacle.distance >= stop_distance {
+                continue;
+            }
+        }
+
+        // Driving through teleop is left to the operator.
+        match proxy.move_status(()).await {
+            Ok(MoveStatusRepBody::Executing { .. }) => {}
+            Ok(MoveStatusRepBody::NoCommand) => continue,
+            Err(e) => {
+                log::warn!("hdcomm RPC error: {}", e);
+                continue;
+            }
+        }
+
+        if let Some(obstacle) = obstacle {
+            log::warn!(
+                "obstacle at {:.3}m within stopping distance of {:.3}m at {:.3}ms^-1, cancelling move",
+                obstacle.distance,
+                stop_distance,
+                velocity
+            );
+        }
+        abort_move(&proxy, &moves, &path).await;
+    }
+}
+
+/// Forwards move lifecycle events from the device to the move tracker.
+async fn track_move_events(mut events: Subscription<MoveEvent>, moves: Arc<MoveTracker>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                log::debug!("move event: {:?}", event);
+                moves.handle_event(&event);
+            }
+            Err(RecvError::Lagged(n)) => {
+                log::warn!("move tracker lagged by {} stream messages", n);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
 }
 
 impl ServerImpl {
     pub async fn new(config: &Config) -> Result<Self, Error> {
-        let (mut router, proxy) =
-            hdcomm_host::connect(&config.serial.name, config.serial.baud).await?;
+        let backoff = Backoff {
+            initial: Duration::from_secs_f64(config.link.reconnect_delay),
+            max: Duration::from_secs_f64(config.link.reconnect_delay_max),
+        };
+        let (mut supervisor, proxy, mut state) =
+            hdcomm_host::connect_supervised(config.endpoint(), backoff);
 
-        let router_handle = tokio::spawn(async move { router.run().await });
+        if let Some(path) = &config.link.record {
+            let (recorder, _) = Recorder::create(path).await.map_err(Error::Recording)?;
+            proxy.set_recorder(Some(recorder));
+            log::info!("recording session to {}", path);
+        }
+
+        let supervisor_handle = tokio::spawn(async move { supervisor.run().await });
 
         let config = config.clone();
 
-        let model = Model {
+        let model = Arc::new(RwLock::new(Model {
             model: config.model.clone(),
             motion: config.motion.clone(),
-        };
+        }));
 
         let sp = Arc::new(Processor::new(proxy.subscribe(), &config));
         let sp_handle = {
@@ -64,36 +942,149 @@ impl ServerImpl {
             tokio::spawn(async move { sp.run().await })
         };
 
-        proxy
-            .pid_param_update(PidParamUpdateReqBody {
-                params: [
-                    config.motion.pid_left.clone(),
-                    config.motion.pid_right.clone(),
-                ],
-                update_interval_ms: (config.motion.pid_update_interval * 1e3) as u16,
-            })
+        log::info!("waiting for device connection");
+        while state.borrow().state != ConnectionState::Connected {
+            if state.borrow().state == ConnectionState::Incompatible {
+                log::error!("device speaks an incompatible protocol version");
+            }
+            state
+                .changed()
+                .await
+                .map_err(|_| Error::SupervisorTerminated)?;
+        }
+
+        log::info!("device connected: {:?}", proxy.device_info());
+
+        upload_pid_params(&proxy, &config.motion)
             .await
             .map_err(|_| Error::InitialParamUpload)?;
 
-        log::info!("sent PID parameters");
+        let front_distance = Arc::new(AtomicBool::new(false));
+        if config.collision.sample_interval > 0. {
+            let enabled =
+                enable_front_distance_stream(&proxy, config.collision.sample_interval).await;
+            front_distance.store(enabled, Ordering::Relaxed);
+        }
+
+        if config.ahrs.bias_calibration_window > 0. {
+            log::info!("calibrating AHRS biases, keep the robot stationary");
+            if let Err(e) = calibrate_bias(&sp, config.ahrs.bias_calibration_window).await {
+                log::warn!("AHRS bias calibration: {}", e.message());
+            }
+        }
+
+        let reconnect_handle = tokio::spawn(handle_reconnects(
+            proxy.clone(),
+            model.clone(),
+            sp.clone(),
+            config.collision.sample_interval,
+            front_distance.clone(),
+            state,
+        ));
+
+        let moves = Arc::new(MoveTracker::new());
+        let move_events_handle = tokio::spawn(track_move_events(
+            proxy.subscribe_to::<MoveEvent>(),
+            moves.clone(),
+        ));
+
+        let teleop = Arc::new(TeleOpControl::new());
+        let move_lease_handle = tokio::spawn(hold_move_lease(
+            proxy.clone(),
+            moves.clone(),
+            teleop.clone(),
+        ));
+
+        let path = Arc::new(PathControl::new());
+        let battery = Arc::new(BatteryMonitor::new(&config.battery));
+        let battery_handle = tokio::spawn(monitor_battery(
+            proxy.clone(),
+            battery.clone(),
+            moves.clone(),
+            path.clone(),
+            config.battery.sample_interval,
+        ));
+        let collision_handle = tokio::spawn(guard_collisions(
+            proxy.clone(),
+            sp.clone(),
+            model.clone(),
+            moves.clone(),
+            path.clone(),
+            config.collision.clone(),
+            front_distance,
+        ));
 
         Ok(Self {
             model,
+            motion_update: Mutex::new(()),
             config,
-            router_handle,
+            supervisor_handle,
             proxy,
             sp,
             sp_handle,
+            reconnect_handle,
+            moves,
+            move_events_handle,
+            path,
+            teleop,
+            move_lease_handle,
+            battery,
+            battery_handle,
+            collision_handle,
         })
     }
 }
 
+impl ServerImpl {
+    /// Refuses new moves while the battery level is low.
+    fn check_battery(&self) -> Result<(), Status> {
+        let level = self.battery.level();
+        if level >= BatteryLevel::Low {
+            return Err(Status::failed_precondition(format!(
+                "battery level {:?}",
+                level
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Generates a move and commands the robot to perform it.
+    ///
+    /// Returns the estimated time required for the move, its sequence
+    /// number, and the time it was accepted at.
+    async fn start_move(&self, request: &MoveRequest) -> Result<(Duration, u64, Instant), Status> {
+        if request.exit_velocity != 0. {
+            return Err(Status::invalid_argument(
+                "exit velocity is only supported for path segments",
+            ));
+        }
+        self.check_battery()?;
+
+        let mrb = self
+            .model
+            .read()
+            .unwrap()
+            .generate_move(request.radius_indexed, request.distance)
+            .map_err(invalid_move)?;
+        let time_required = Duration::from_secs_f32(mrb.time_required());
+        let (seq, started) = send_move(&self.proxy, &self.moves, mrb).await?;
+
+        Ok((time_required, seq, started))
+    }
+}
+
 impl Drop for ServerImpl {
     /// A custom Drop implementation is provided that destroys all background
     /// tasks associated with the server.
     fn drop(&mut self) {
-        self.router_handle.abort();
+        self.supervisor_handle.abort();
         self.sp_handle.abort();
+        self.reconnect_handle.abort();
+        self.move_events_handle.abort();
+        self.move_lease_handle.abort();
+        self.battery_handle.abort();
+        self.collision_handle.abort();
     }
 }
 
@@ -105,35 +1096,273 @@ impl HdComm for ServerImpl {
     ) -> Result<Response<MoveResponse>, Status> {
         log::info!("move() request: {:?}", request);
 
-        match self
+        let (time_required, _, _) = self.start_move(request.get_ref()).await?;
+
+        Ok(Response::new(MoveResponse {
+            time_required: Some(time_required.into()),
+        }))
+    }
+
+    async fn move_and_wait(
+        &self,
+        request: Request<MoveAndWaitRequest>,
+    ) -> Result<Response<MoveAndWaitResponse>, Status> {
+        log::info!("move_and_wait() request: {:?}", request);
+
+        let request = request.into_inner();
+        let move_request = request
+            .request
+            .ok_or_else(|| Status::invalid_argument("move request missing"))?;
+        let timeout = request
+            .timeout
+            .map(Duration::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("timeout must not be negative"))?;
+
+        let (time_required, seq, started) = self.start_move(&move_request).await?;
+        let timeout = timeout.unwrap_or(time_required + MOVE_TIMEOUT_MARGIN);
+        let (outcome, duration) =
+            wait_move(&self.proxy, &self.moves, seq, started, timeout).await?;
+
+        Ok(Response::new(MoveAndWaitResponse {
+            outcome: move_and_wait_response::Outcome::from(outcome) as i32,
+            time_required: Some(time_required.into()),
+            duration: Some(duration.into()),
+        }))
+    }
+
+    async fn move_with_heading_correction(
+        &self,
+        request: Request<HeadingCorrectedMoveRequest>,
+    ) -> Result<Response<HeadingCorrectedMoveResponse>, Status> {
+        log::info!("move_with_heading_correction() request: {:?}", request);
+
+        let request = request.into_inner();
+        let move_request = request
+            .request
+            .ok_or_else(|| Status::invalid_argument("move request missing"))?;
+        let radius = move_request.radius_indexed;
+        let tolerance = if request.tolerance > 0. {
+            request.tolerance
+        } else {
+            self.config.motion.heading_tolerance
+        };
+        let target = self
             .model
-            .generate_move(request.get_ref().radius_indexed, request.get_ref().distance)
-        {
-            Ok(mrb) => {
-                let time_required: GrpcDuration =
-                    Duration::from_secs_f32(mrb.time_required()).into();
-
-                match self.proxy.move_cmd(mrb).await {
-                    Ok(rpc::MoveRepBody::Accepted) => Ok(Response::new(MoveResponse {
-                        time_required: Some(time_required),
-                    })),
-                    Ok(rpc::MoveRepBody::Busy) => Err(Status::unavailable("move in progress")),
-                    Err(e) => {
-                        log::warn!("hdcomm RPC error: {}", e);
-                        Err(Status::internal(e.to_string()))
+            .read()
+            .unwrap()
+            .heading_change(radius, move_request.distance)
+            .map_err(|_| Status::invalid_argument("radius not supported"))?
+            .to_degrees();
+
+        let initial = self.sp.heading();
+        if initial.timestamp.is_none() {
+            return Err(Status::failed_precondition("no orientation estimate"));
+        }
+
+        let started = Instant::now();
+        let mut next = move_request;
+        let mut corrections = 0;
+
+        let (outcome, heading_change) = loop {
+            let (time_required, seq, move_started) = self.start_move(&next).await?;
+            let timeout = time_required + MOVE_TIMEOUT_MARGIN;
+            let (outcome, _) =
+                wait_move(&self.proxy, &self.moves, seq, move_started, timeout).await?;
+
+            // Let the orientation estimate catch up with the end of the move.
+            tokio::time::sleep(HEADING_SETTLE_TIME).await;
+            let heading_change = self.sp.heading().unwrapped - initial.unwrapped;
+            let error = target - heading_change;
+
+            if outcome != Outcome::Completed
+                || radius == 0
+                || error.abs() <= tolerance
+                || corrections >= self.config.motion.max_heading_corrections
+            {
+                break (outcome, heading_change);
+            }
+
+            // Continue along the same arc, reversing on overshoots.
+            let distance = self
+                .model
+                .read()
+                .unwrap()
+                .distance_for_heading_change(radius, error.to_radians())
+                .map_err(|_| Status::invalid_argument("radius not supported"))?;
+            log::info!(
+                "heading error of {:.2} degrees, correcting with a {:.3}m move",
+                error,
+                distance
+            );
+
+            next = MoveRequest {
+                radius_indexed: radius,
+                distance,
+                exit_velocity: 0.,
+            };
+            corrections += 1;
+        };
+
+        Ok(Response::new(HeadingCorrectedMoveResponse {
+            outcome: move_and_wait_response::Outcome::from(outcome) as i32,
+            target_heading_change: target,
+            heading_change,
+            within_tolerance: (target - heading_change).abs() <= tolerance,
+            corrections,
+            duration: Some(started.elapsed().into()),
+        }))
+    }
+
+    type ExecutePathStream = ReceiverStream<Result<PathProgress, Status>>;
+
+    async fn execute_path(
+        &self,
+        request: Request<ExecutePathRequest>,
+    ) -> Result<Response<Self::ExecutePathStream>, Status> {
+        log::info!("execute_path() request: {:?}", request);
+
+        let requests = request.into_inner().segments;
+        if requests.is_empty() {
+            return Err(Status::invalid_argument("path has no segments"));
+        }
+        self.check_battery()?;
+
+        // Generate all segments from the same motion profile.
+        let model = self.model.read().unwrap();
+        let mut segments = Vec::with_capacity(requests.len());
+        let mut entry_velocity = 0.;
+        for (index, segment) in requests.iter().enumerate() {
+            let invalid =
+                |message: &str| Status::invalid_argument(format!("segment {}: {}", index, message));
+
+            // Consecutive segments must agree on the velocity at their
+            // boundary, so that the robot does not change direction at
+            // speed.
+            let exit_velocity = segment.exit_velocity;
+            if exit_velocity != 0. {
+                match requests.get(index + 1) {
+                    Some(next) if (next.distance < 0.) == (segment.distance < 0.) => {}
+                    Some(_) => {
+                        return Err(invalid(
+                            "non-zero exit velocity requires the next segment to move in \
+                             the same direction",
+                        ))
                     }
+                    None => return Err(invalid("the last segment must end at zero velocity")),
                 }
             }
-            Err(ModelError::RadiusNotSupported) => {
-                Err(Status::invalid_argument("radius not supported"))
+
+            let mrb = model
+                .generate_blended_move(
+                    segment.radius_indexed,
+                    segment.distance,
+                    entry_velocity,
+                    exit_velocity,
+                )
+                .map_err(|e| invalid(invalid_move(e).message()))?;
+            segments.push(mrb);
+            entry_velocity = exit_velocity;
+        }
+        drop(model);
+
+        let blended = requests.iter().any(|segment| segment.exit_velocity != 0.);
+        if blended {
+            match self.proxy.device_info() {
+                Some(device) if device.version >= BLENDED_MOVES_VERSION => {}
+                _ => {
+                    return Err(Status::failed_precondition(
+                        "device does not support non-zero exit velocities",
+                    ))
+                }
             }
-            _ => unreachable!(),
         }
+
+        let path = self
+            .path
+            .begin()
+            .ok_or_else(|| Status::unavailable("path in progress"))?;
+
+        let (tx, rx) = mpsc::channel(PATH_PROGRESS_BUFFER_SIZE);
+        tokio::spawn(execute_path(
+            self.proxy.clone(),
+            self.moves.clone(),
+            path,
+            segments,
+            tx,
+        ));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn pause_path(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        log::info!("pause_path() request");
+
+        if !self.path.command(PathCommand::Pause) {
+            return Err(Status::failed_precondition("no path in progress"));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn resume_path(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        log::info!("resume_path() request");
+
+        if !self.path.command(PathCommand::Run) {
+            return Err(Status::failed_precondition("no path in progress"));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn cancel_path(&self, _: Request<()>) -> Result<Response<()>, Status> {
+        log::info!("cancel_path() request");
+
+        if !self.path.command(PathCommand::Cancel) {
+            return Err(Status::failed_precondition("no path in progress"));
+        }
+
+        self.moves.cancelled();
+
+        if let Err(e) = self.proxy.move_cancel(()).await {
+            log::warn!("hdcomm RPC error: {}", e);
+            return Err(Status::internal(e.to_string()));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    type TeleOpStream = ReceiverStream<Result<TeleOpStatus, Status>>;
+
+    async fn tele_op(
+        &self,
+        request: Request<Streaming<TeleOpCommand>>,
+    ) -> Result<Response<Self::TeleOpStream>, Status> {
+        log::info!("tele_op() request");
+
+        let session = self
+            .teleop
+            .begin()
+            .ok_or_else(|| Status::unavailable("teleop in progress"))?;
+        let deadman = Duration::from_secs_f64(self.config.teleop.deadman_interval);
+
+        let (tx, rx) = mpsc::channel(TELEOP_STATUS_BUFFER_SIZE);
+        tokio::spawn(teleop(
+            self.proxy.clone(),
+            session,
+            request.into_inner(),
+            deadman,
+            tx,
+        ));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn move_cancel(&self, _: Request<()>) -> Result<Response<()>, tonic::Status> {
         log::info!("move_cancel() request");
 
+        self.moves.cancelled();
+
         if let Err(e) = self.proxy.move_cancel(()).await {
             log::warn!("hdcomm RPC error: {}", e);
             Err(Status::internal(e.to_string()))
@@ -183,7 +1412,15 @@ impl HdComm for ServerImpl {
         log::info!("get_radii() request");
 
         let mut radii = vec![f64::INFINITY];
-        radii.extend(self.model.model.turn_radii.iter().map(|r| r.radius));
+        radii.extend(
+            self.model
+                .read()
+                .unwrap()
+                .model
+                .turn_radii
+                .iter()
+                .map(|r| r.radius),
+        );
 
         Ok(Response::new(RadiiResponse { radii }))
     }
@@ -204,6 +1441,147 @@ impl HdComm for ServerImpl {
         }))
     }
 
+    async fn set_heading_reference(
+        &self,
+        request: Request<SetHeadingReferenceRequest>,
+    ) -> Result<Response<UnwrappedHeadingResponse>, Status> {
+        log::info!("set_heading_reference() request: {:?}", request);
+
+        let reading = self.sp.set_heading(request.into_inner().heading);
+
+        Ok(Response::new(UnwrappedHeadingResponse {
+            device_time: reading.timestamp.unwrap_or(f64::NAN),
+            heading: reading.heading,
+            unwrapped_heading: reading.unwrapped,
+        }))
+    }
+
+    async fn get_unwrapped_heading(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<Response<UnwrappedHeadingResponse>, Status> {
+        let reading = self.sp.heading();
+
+        Ok(Response::new(UnwrappedHeadingResponse {
+            device_time: reading.timestamp.unwrap_or(f64::NAN),
+            heading: reading.heading,
+            unwrapped_heading: reading.unwrapped,
+        }))
+    }
+
+    async fn get_angular_rate(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<Response<AngularRateResponse>, Status> {
+        let reading = self.sp.heading();
+
+        Ok(Response::new(AngularRateResponse {
+            device_time: reading.timestamp.unwrap_or(f64::NAN),
+            yaw_rate: reading.rate,
+        }))
+    }
+
+    async fn get_pose(&self, _: tonic::Request<()>) -> Result<Response<PoseResponse>, Status> {
+        let pose = self.sp.pose();
+
+        Ok(Response::new(PoseResponse {
+            device_time: pose.timestamp.unwrap_or(f64::NAN),
+            x: pose.x,
+            y: pose.y,
+            theta: pose.theta.to_degrees(),
+        }))
+    }
+
+    type WatchOrientationStream = ReceiverStream<Result<OrientationUpdate, Status>>;
+
+    async fn watch_orientation(
+        &self,
+        request: Request<WatchOrientationRequest>,
+    ) -> Result<Response<Self::WatchOrientationStream>, Status> {
+        log::info!("watch_orientation() request: {:?}", request);
+
+        let request = request.into_inner();
+        let decimation = request.decimation.max(1);
+        let mut estimates = self.sp.subscribe();
+        let (tx, rx) = mpsc::channel(ORIENTATION_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                let estimate = tokio::select! {
+                    // Subscriber went away.
+                    _ = tx.closed() => break,
+                    estimate = estimates.recv() => estimate,
+                };
+                let estimate = match estimate {
+                    Ok(estimate) => estimate,
+                    Err(RecvError::Lagged(n)) => {
+                        log::warn!("orientation subscriber lagged by {} estimates", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                received += 1;
+                if received < decimation {
+                    continue;
+                }
+                received = 0;
+
+                let update = OrientationUpdate::new(&estimate, request.include_samples);
+                if tx.send(Ok(update)).await.is_err() {
+                    // Subscriber went away.
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_ahrs_stats(
+        &self,
+        _: tonic::Request<()>,
+    ) -> Result<Response<AhrsStatsResponse>, Status> {
+        let stats = self.sp.ahrs_stats();
+
+        Ok(Response::new(AhrsStatsResponse {
+            received: stats.received,
+            lost: stats.lost,
+            gaps: stats.gaps,
+            out_of_order: stats.out_of_order,
+            lagged: stats.lagged,
+        }))
+    }
+
+    async fn calibrate_imu(
+        &self,
+        request: Request<CalibrateImuRequest>,
+    ) -> Result<Response<CalibrateImuResponse>, Status> {
+        log::info!("calibrate_imu() request: {:?}", request);
+
+        let window = request
+            .into_inner()
+            .window
+            .map(Duration::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("window must not be negative"))?;
+        let window = match window {
+            Some(window) => window.as_secs_f64(),
+            None if self.config.ahrs.bias_calibration_window > 0. => {
+                self.config.ahrs.bias_calibration_window
+            }
+            None => DEFAULT_BIAS_CALIBRATION_WINDOW,
+        };
+
+        let bias = calibrate_bias(&self.sp, window).await?;
+
+        Ok(Response::new(CalibrateImuResponse {
+            gyro_bias: Some((&bias.gyro).into()),
+            acc_bias: Some((&bias.acc).into()),
+        }))
+    }
+
     async fn get_front_distance(
         &self,
         _: tonic::Request<()>,
@@ -224,6 +1602,15 @@ impl HdComm for ServerImpl {
         }
     }
 
+    async fn get_obstacle(&self, _: Request<()>) -> Result<Response<ObstacleResponse>, Status> {
+        let obstacle = self.sp.obstacle();
+
+        Ok(Response::new(ObstacleResponse {
+            device_time: obstacle.timestamp.unwrap_or(f64::NAN),
+            distance: obstacle.distance,
+        }))
+    }
+
     async fn get_vin_reading(
         &self,
         _: tonic::Request<()>,
@@ -239,4 +1626,113 @@ impl HdComm for ServerImpl {
             }
         }
     }
+
+    async fn get_motion_params(&self, _: Request<()>) -> Result<Response<MotionParams>, Status> {
+        log::info!("get_motion_params() request");
+
+        let params = MotionParams::from(&self.model.read().unwrap().motion);
+
+        Ok(Response::new(params))
+    }
+
+    async fn set_motion_params(
+        &self,
+        request: Request<MotionParams>,
+    ) -> Result<Response<MotionParams>, Status> {
+        log::info!("set_motion_params() request: {:?}", request);
+
+        let params = request.into_inner();
+        let (pid_left, pid_right) = match (&params.pid_left, &params.pid_right) {
+            (Some(left), Some(right)) => (left.into(), right.into()),
+            _ => return Err(Status::invalid_argument("PID parameters missing")),
+        };
+
+        let _update = self.motion_update.lock().await;
+
+        // Validate the parameters against a copy of the model, so that
+        // nothing changes unless all of them are accepted.
+        let current = self.model.read().unwrap().clone();
+        let mut model = current.clone();
+        model
+            .set_motion_profile_limits(params.max_jerk, params.max_accel, params.max_velocity)
+            .and_then(|_| model.set_steering_setup_time(params.steering_setup_time))
+            .and_then(|_| model.set_pid_params(pid_left, pid_right))
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        if model.motion.pid_left != current.motion.pid_left
+            || model.motion.pid_right != current.motion.pid_right
+        {
+            match upload_pid_params(&self.proxy, &model.motion).await {
+                Ok(PidParamUpdateRepBody::Updated) => {}
+                Ok(PidParamUpdateRepBody::Busy) => {
+                    return Err(Status::unavailable("move in progress"))
+                }
+                Err(e) => {
+                    log::warn!("hdcomm RPC error: {}", e);
+                    return Err(Status::internal(e.to_string()));
+                }
+            }
+        }
+
+        let params = MotionParams::from(&model.motion);
+        *self.model.write().unwrap() = model;
+
+        Ok(Response::new(params))
+    }
+
+    type WatchBatteryStream = ReceiverStream<Result<BatteryUpdate, Status>>;
+
+    async fn watch_battery(
+        &self,
+        request: Request<WatchBatteryRequest>,
+    ) -> Result<Response<Self::WatchBatteryStream>, Status> {
+        log::info!("watch_battery() request: {:?}", request);
+
+        // Subscribe before retrieving the history, so that no reading is
+        // missed in between.
+        let mut readings = self.battery.subscribe();
+        let history = if request.into_inner().include_history {
+            self.battery.history()
+        } else {
+            Vec::new()
+        };
+        let (tx, rx) = mpsc::channel(BATTERY_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            let last = history.last().copied();
+            for reading in history.iter() {
+                if tx.send(Ok(reading.into())).await.is_err() {
+                    return;
+                }
+            }
+
+            loop {
+                let reading = tokio::select! {
+                    // Subscriber went away.
+                    _ = tx.closed() => break,
+                    reading = readings.recv() => reading,
+                };
+                let reading = match reading {
+                    Ok(reading) => reading,
+                    Err(RecvError::Lagged(n)) => {
+                        log::warn!("battery subscriber lagged by {} readings", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                // Already sent as part of the history.
+                if Some(reading) == last {
+                    continue;
+                }
+
+                if tx.send(Ok((&reading).into())).await.is_err() {
+                    // Subscriber went away.
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 }
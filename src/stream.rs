@@ -1,9 +1,25 @@
 /// Processing for stream messages received from the device.
-use crate::ahrs::{Angles, Filter};
+use crate::ahrs::{Angles, Estimate, Filter, Heading, HeadingReading, SampleStats};
+use crate::calibration::{Bias, BiasEstimator, Error as CalibrationError};
 use crate::config::Config;
-use hdcomm_core::stream::Payload;
+use crate::obstacle::{Obstacle, ObstacleFilter};
+use crate::odometry::{Odometry, Pose};
+use hdcomm_core::stream::{AhrsBody, Payload};
 use std::sync::RwLock;
-use tokio::sync::{broadcast::Receiver, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::sync::{oneshot, Mutex};
+
+/// Number of orientation estimates buffered for each subscriber.
+const ESTIMATE_BUFFER_SIZE: usize = 256;
+
+/// Ongoing gyroscope & accelerometer bias calibration.
+struct BiasCalibration {
+    /// Bias estimator.
+    estimator: BiasEstimator,
+    /// Calibration result sender.
+    done: oneshot::Sender<Result<Bias, CalibrationError>>,
+}
 
 /// Stream message processor.
 ///
@@ -14,6 +30,16 @@ pub struct Processor {
     src: Mutex<Receiver<Payload>>,
     /// AHRS filter.
     filter: RwLock<Filter>,
+    /// Orientation estimate broadcast.
+    estimates: Sender<Estimate>,
+    /// Relative heading tracker.
+    heading: RwLock<Heading>,
+    /// Dead-reckoning pose estimator.
+    odometry: RwLock<Odometry>,
+    /// Obstacle estimator.
+    obstacle: RwLock<ObstacleFilter>,
+    /// Ongoing bias calibration.
+    calibration: std::sync::Mutex<Option<BiasCalibration>>,
 }
 
 impl Processor {
@@ -22,6 +48,11 @@ impl Processor {
         Self {
             src: Mutex::new(src),
             filter: RwLock::new(Filter::new(&config.ahrs)),
+            estimates: broadcast::channel(ESTIMATE_BUFFER_SIZE).0,
+            heading: RwLock::new(Heading::default()),
+            odometry: RwLock::new(Odometry::new(&config.model)),
+            obstacle: RwLock::new(ObstacleFilter::new(config.collision.filter_window)),
+            calibration: std::sync::Mutex::new(None),
         }
     }
 
@@ -37,8 +68,29 @@ impl Processor {
         loop {
             match rx.recv().await {
                 Ok(msg) => match msg {
-                    Payload::Ahrs(raw) => self.filter.write().unwrap().update(&raw),
+                    Payload::Ahrs(raw) => {
+                        self.calibrate_with(&raw);
+                        let estimate = self.filter.write().unwrap().update(&raw);
+                        if let Some(estimate) = estimate {
+                            self.heading.write().unwrap().update(&estimate);
+                            // Sending only fails when there are no subscribers.
+                            let _ = self.estimates.send(estimate);
+                        }
+                    }
+                    Payload::Move(_) | Payload::Failsafe(_) => {}
+                    Payload::Odometry(body) => {
+                        let angles = self.orientation();
+                        let yaw = angles.timestamp.map(|_| angles.yaw.to_radians());
+                        self.odometry.write().unwrap().update(&body, yaw);
+                    }
+                    Payload::FrontDistance(body) => {
+                        self.obstacle.write().unwrap().update(&body);
+                    }
                 },
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("stream processor lagged by {} messages", n);
+                    self.filter.write().unwrap().record_lagged(n);
+                }
                 Err(e) => {
                     log::warn!("receive: {}", e);
                 }
@@ -46,8 +98,106 @@ impl Processor {
         }
     }
 
+    /// Feed a raw AHRS sample to the ongoing bias calibration, if any.
+    ///
+    /// Biases are applied to the filter once estimated.
+    fn calibrate_with(&self, raw: &AhrsBody) {
+        let mut calibration = self.calibration.lock().unwrap();
+        let result = match calibration.as_mut().and_then(|c| c.estimator.push(raw)) {
+            Some(result) => result,
+            None => return,
+        };
+
+        if let Ok(bias) = &result {
+            self.filter.write().unwrap().set_bias(bias);
+        }
+        if let Some(c) = calibration.take() {
+            // Sending only fails if the calibration was abandoned.
+            let _ = c.done.send(result);
+        }
+    }
+
+    /// Re-synchronize the AHRS filter with the device clock, e.g. once the
+    /// device reconnects.
+    pub fn resync(&self) {
+        self.filter.write().unwrap().resync();
+    }
+
+    /// Estimate the gyroscope & accelerometer biases over `window` seconds
+    /// of device time.
+    ///
+    /// The robot must be stationary and level during the calibration. The
+    /// biases are removed from subsequent samples once estimated. Samples
+    /// received in the meantime are processed with the previous biases.
+    ///
+    /// Dropping the returned future abandons the calibration.
+    pub async fn calibrate(&self, window: f64) -> Result<Bias, CalibrationError> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut calibration = self.calibration.lock().unwrap();
+            if let Some(c) = calibration.as_ref() {
+                if !c.done.is_closed() {
+                    return Err(CalibrationError::InProgress);
+                }
+            }
+
+            let config = self.filter.read().unwrap().config().clone();
+            *calibration = Some(BiasCalibration {
+                estimator: BiasEstimator::new(&config, window),
+                done: tx,
+            });
+        }
+
+        // The sender is only dropped after sending the result, or when
+        // replaced after this future is dropped.
+        rx.await.expect("bias calibration result")
+    }
+
     /// Retrieve the latest orientation reading.
     pub fn orientation(&self) -> Angles {
         self.filter.read().unwrap().euler_angles()
     }
+
+    /// Retrieve the latest heading reading, relative to the heading
+    /// reference.
+    pub fn heading(&self) -> HeadingReading {
+        self.heading.read().unwrap().reading()
+    }
+
+    /// Re-reference the heading, such that the current heading becomes
+    /// `heading` degrees.
+    ///
+    /// Returns the re-referenced heading reading.
+    pub fn set_heading(&self, heading: f64) -> HeadingReading {
+        let mut tracker = self.heading.write().unwrap();
+        tracker.set(heading);
+        tracker.reading()
+    }
+
+    /// Retrieve the AHRS sample statistics.
+    pub fn ahrs_stats(&self) -> SampleStats {
+        self.filter.read().unwrap().stats()
+    }
+
+    /// Retrieve the latest pose estimate.
+    pub fn pose(&self) -> Pose {
+        self.odometry.read().unwrap().pose()
+    }
+
+    /// Retrieve the latest obstacle estimate.
+    pub fn obstacle(&self) -> Obstacle {
+        self.obstacle.read().unwrap().obstacle()
+    }
+
+    /// Retrieve the latest obstacle estimate, unless no front distance
+    /// reading was received within `max_age`.
+    pub fn recent_obstacle(&self, max_age: Duration) -> Option<Obstacle> {
+        self.obstacle.read().unwrap().recent_obstacle(max_age)
+    }
+
+    /// Subscribe to the orientation estimates produced for every AHRS sample
+    /// received.
+    pub fn subscribe(&self) -> Receiver<Estimate> {
+        self.estimates.subscribe()
+    }
 }
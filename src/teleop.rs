@@ -0,0 +1,89 @@
+/// Teleoperation control.
+///
+/// Clients stream raw actuator commands, which are forwarded to the device.
+/// At most one client teleoperates the robot at any one time, and the wheels
+/// are stopped once its commands stop arriving.
+use hdcomm_core::rpc::RawTeleOpReqBody;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Controls access to teleoperation.
+pub struct TeleOpControl {
+    /// Whether a client is teleoperating the robot.
+    active: AtomicBool,
+}
+
+impl TeleOpControl {
+    /// Create a new teleoperation controller.
+    pub fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Begin a teleoperation session.
+    ///
+    /// Returns `None` if another session is in progress.
+    pub fn begin(self: &Arc<Self>) -> Option<TeleOpSession> {
+        if self.active.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        Some(TeleOpSession {
+            control: self.clone(),
+            duty: [0.; 2],
+        })
+    }
+
+    /// Whether a teleoperation session is in progress.
+    pub fn active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+impl Default for TeleOpControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Teleoperation session in progress.
+///
+/// Dropping the session allows another session to begin.
+pub struct TeleOpSession {
+    control: Arc<TeleOpControl>,
+    /// Left & right wheel duty cycles last applied by the device.
+    duty: [f32; 2],
+}
+
+impl TeleOpSession {
+    /// Record a command applied by the device.
+    pub fn applied(&mut self, command: &RawTeleOpReqBody) {
+        if let Some(duty) = command.wheel_l {
+            self.duty[0] = duty;
+        }
+        if let Some(duty) = command.wheel_r {
+            self.duty[1] = duty;
+        }
+    }
+
+    /// Whether the wheels may be driving.
+    pub fn driving(&self) -> bool {
+        self.duty != [0.; 2]
+    }
+}
+
+impl Drop for TeleOpSession {
+    fn drop(&mut self) {
+        self.control.active.store(false, Ordering::Release);
+    }
+}
+
+/// Command stopping both wheels, leaving the steering untouched.
+pub fn stop_command() -> RawTeleOpReqBody {
+    RawTeleOpReqBody {
+        steering: None,
+        wheel_l: Some(0.),
+        wheel_r: Some(0.),
+    }
+}